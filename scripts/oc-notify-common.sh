@@ -0,0 +1,4240 @@
+# Shared helpers for oc-run-notify.sh and oc-run-notify-ssh.sh.
+# Sourced by both wrappers; not meant to be executed directly.
+#
+# Common options:
+#   --label <text>        Name used in messages (default: job / remote-job)
+#   --channel <name>      OpenClaw channel (required with the openclaw sink)
+#   --target <id>         OpenClaw target (required with the openclaw sink)
+#   --sink <spec>         Where notifications go; repeatable. A spec is
+#                         NAME[:FILTER] or a comma-separated list of them.
+#                         Sinks: openclaw (default), desktop, ntfy, webhook,
+#                         email. FILTER is a +-separated list of event names
+#                         or classes: all, final (completed/failed/
+#                         interrupted/timeout), failure, warning (warning or
+#                         critical), critical. Default filter is all, except
+#                         email which defaults to final.
+#                         e.g. --sink openclaw --sink email:failure
+#   --notify <mode>       all (default), on-done (only the final message) or
+#                         on-failure (only a final failure, timeout or
+#                         interruption) for every sink, for CI-style runs
+#   --min-duration <dur>[:all]
+#                         Send no final message for a run that completes in
+#                         less than this, e.g. to wrap every make in an
+#                         alias; failures are still reported unless :all is
+#                         given. Messages sent during the run are not held
+#   --ntfy-topic <topic>  ntfy topic name or full topic URL for the ntfy sink
+#   --ntfy-priority <map> Like --openclaw-priority for ntfy (silent is ntfy's
+#                         min priority: no sound or vibration); also
+#                         OCNOTIFY_NTFY_PRIORITY. Default critical=high,
+#                         progress=silent,heartbeat=silent
+#   --openclaw-format <f> markdown, slack (mrkdwn) or plain; default is slack
+#                         for --channel slack and markdown otherwise
+#   --openclaw-priority <map>
+#                         Event severity or name to OpenClaw priority, e.g.
+#                         critical=high,info=low,progress=silent (levels:
+#                         high, normal, low, silent; a name beats its
+#                         severity); default critical=high,progress=silent,
+#                         heartbeat=silent, so milestones and heartbeats
+#                         arrive quietly (e.g. Telegram silent messages).
+#                         Also settable per channel as
+#                         OCNOTIFY_OPENCLAW_PRIORITY_<CHANNEL>. Only passed
+#                         if the openclaw CLI supports it
+#   --log <path>          Append child output to this file (alias --log-file)
+#   --log-timestamps      Prefix each log line with its UTC time
+#   --log-max-size <size> Rotate the log once it passes this size (e.g. 100M),
+#                         keeping <path>.1 .. <path>.3
+#   --cost-rate <r[/h]>   Hourly price of the machine, e.g. 3.50/h or 0.06/m
+#   --max-cost <amount>   Alert once when accumulated cost passes this budget
+#   --webhook <url>       URL for the webhook sink (JSON POST per event);
+#                         adds the sink if --sink does not mention it
+#   --watch-preemption    Poll EC2/GCE metadata for spot preemption notices
+#   --on-preempt <cmd>    Shell command run once on the job host when a
+#                         preemption notice appears (e.g. trigger a checkpoint)
+#   --watch-battery [pct] Warn when this machine switches to battery power and
+#                         when the battery drops below pct% (default 20);
+#                         polled every 30s (Linux sysfs, macOS pmset)
+#   --on-success <cmd>    Shell command run on the job host after a clean exit
+#   --on-failure <cmd>    ... after a failure, timeout or interruption
+#                         (Ctrl-C, SIGTERM, SIGHUP). Hooks get
+#                         OCNOTIFY_LABEL and OCNOTIFY_EXIT in the environment;
+#                         their exit status and last output line are listed
+#                         in the final message
+#   --hook-timeout <dur>  Stop a hook that runs longer than this (default 60s)
+#   --plot <path>         Image on the job host attached to the final message
+#   --metric <name=regex> Record a metric from output lines, e.g.
+#                         'loss=loss[:=]\s*([0-9.eE+-]+)'; the first capture
+#                         group (or the whole match) is the value. Bash ERE
+#                         plus \s \d \w; repeatable. The last value of each
+#                         metric is shown in the final message, with a
+#                         sparkline of the first one. Values with units are
+#                         turned into plain numbers: 1.2k, 3.4M, 2.5GiB
+#                         (bytes), 1h23m or 1:23:00 (seconds), 45%, 1,234
+#   --metrics-file <path> Also append every recorded value there, as JSONL
+#                         if the name ends in .jsonl, CSV otherwise
+#                         (time,elapsed_sec,metric,value)
+#   --metric-goal <name=min|max>
+#                         Whether lower or higher is better for a metric, so
+#                         changes are marked ✓ (improving) or ✗ (worsening);
+#                         comma-separated or repeatable, e.g. loss=min,acc=max.
+#                         Messages show each metric with its change since the
+#                         last message that reported it (or since the first
+#                         value), e.g. "loss=0.31 (↓0.02 ✓)"
+#   --best <name>         Track the best value of a --metric (direction from
+#                         --metric-goal, default max) across runs with the
+#                         same label, send "🏆 new best" when a run beats it
+#                         (at most every 10 minutes) and show it at the end.
+#                         History lives in ~/.local/state/ocnotify/best/
+#   --history <name>      Keep the last value of a metric from each
+#                         completed run with the same label (the last 20,
+#                         in ~/.local/state/ocnotify/history/) and compare
+#                         it with the median of the previous 5 at the end,
+#                         e.g. "coverage=81.2 (↓1.8 ✗ vs median 83 of 5
+#                         runs)". A value worse than that by more than
+#                         --regression-tolerance sends a 📉 regression
+#                         warning. Globs like 'bench.*' match several
+#                         metrics; direction from --metric-goal, default max
+#                         (min for bench.*). Repeatable
+#   --regression-tolerance <percent>
+#                         How much worse than the median a --history metric
+#                         may get before it counts as a regression (default
+#                         5)
+#   --no-host-context     Leave out the "Host context" line (load, memory,
+#                         top CPU users, busy or full disks on the job host)
+#                         that failure, timeout and stall messages get
+#   --no-profile          Do not learn this label's typical duration. By
+#                         default the last 20 successful runs per label are
+#                         kept (~/.local/state/ocnotify/durations/); once
+#                         there are 3, a run that goes past 1.5x the median
+#                         and past the longest of them sends one ⏳ warning,
+#                         and the final message compares the duration.
+#                         oc-notify-ctl.sh forget <label> resets it
+#   --expected <dur>      How long the command usually takes. While it reports
+#                         no progress, messages and status.json show an
+#                         estimate from the elapsed time, e.g. "~40% by
+#                         time" (default: the label's typical duration)
+#   --plot-metrics        Chart the --metric values over time and attach it
+#                         to the final message when there is no --plot (PNG
+#                         with gnuplot installed, SVG otherwise)
+#   --alert <rule>        Warn when a --metric crosses a threshold, e.g.
+#                         'loss > 10' or 'acc < 0.5 after 2h' (only checked
+#                         once the run is that old); operators < <= > >= ==
+#                         != ('after 50%' waits for reported progress).
+#                         Fires again only after the value recovers
+#   --parser <names>      Read progress from the output of known tools:
+#                         tqdm, cargo, pytest, ffmpeg, rsync (--info=progress2
+#                         or --progress), docker, pip (summary only), wget,
+#                         curl, terraform; comma-separated. The default, auto,
+#                         picks those named in the command plus tqdm; "none"
+#                         turns parsing off. Three more record metrics for
+#                         --history instead: tarpaulin and coverage
+#                         (coverage.py or pytest-cov report) the total as
+#                         "coverage" in percent, criterion each benchmark's
+#                         time in seconds as "bench.<name>" ("bench.a.b"
+#                         for a/b)
+#   --parse-rule <rule>   Read progress from lines matching a regex with named
+#                         groups, e.g. 'Epoch (?P<current>\d+)/(?P<total>\d+)
+#                         loss=(?P<loss>[0-9.]+) => epoch {current}'.
+#                         Groups named percent, current, total and summary
+#                         fill the report, others are recorded as metrics
+#                         (units are handled as for --metric), and the text
+#                         after " => " is the summary with {name}
+#                         placeholders. Repeatable; the first matching
+#                         rule wins. OCNOTIFY_PARSE_RULES=(...) in the config
+#                         file adds rules after these
+#   --parser-cmd <cmd>    Every 10s, pipe the output since the last call to
+#                         this shell command (run locally) and read a progress
+#                         report from the first JSON object it prints, same
+#                         keys as the progress protocol below
+#   --parser-timeout <dur> Stop a --parser-cmd call after this (default 10s);
+#                         failed calls are counted in the final message
+#   --progress-from-file <path:size>
+#                         Estimate progress from the size of an output file on
+#                         the job host against its expected final size (e.g.
+#                         out.bin:120G), checked every 10s; for jobs that
+#                         write a known file but log nothing
+#   --progress-from-count <'glob':N>
+#                         Estimate progress from how many files on the job
+#                         host match a glob (** allowed) against the expected
+#                         count, e.g. 'frames/*.png':14400, checked every 10s
+#   --progress-sources <list>
+#                         Combine several progress sources into one percent:
+#                         output (protocol, --parser, --parse-rule and
+#                         --parser-cmd), file and count, each with an optional
+#                         weight, e.g. output=1,file=3. Unlisted sources are
+#                         ignored
+#   --progress-policy <weighted|first|max>
+#                         How --progress-sources are combined: weighted
+#                         average with silent sources at 0% (default), the
+#                         first listed source that has reported, or the
+#                         highest percent
+#   --progress-smoothing <raw|monotonic|ewma>
+#                         For tools whose percent jumps around: take it as
+#                         reported (default), never let it fall below the
+#                         highest so far in the phase, or that plus a
+#                         remaining time from an exponentially weighted rate
+#                         (2-minute half-life) instead of the pace of the
+#                         last 10 minutes
+#   --phases <list>       Declare the job's phases, e.g. "build,test,deploy";
+#                         the run starts in the first and moves to a later
+#                         one when an output line has a word starting with
+#                         its name (any case), or matches its awk ERE with
+#                         name=regex entries. Messages read "Phase 2/3: test
+#                         — 60%" and the final one lists time per phase.
+#                         Progress and milestones start over in each phase
+#   --step <cmd>          Run these shell commands in order instead of a
+#                         command after --, stopping at the first failure;
+#                         repeatable, or OCNOTIFY_STEPS=(...) in the config.
+#                         Each step is a phase ("Step 2/3: make test") and
+#                         the final message lists every step's result and
+#                         duration. Not with --phases
+#   --keep-going          Run the remaining steps after one fails (the exit
+#                         status is still the first failure's)
+#   --approve-before <regex>
+#                         Hold the --step commands matching this ERE until
+#                         someone approves: a ⏸️ message asks, and
+#                         oc-notify-ctl.sh <run> approve (or reject) answers.
+#                         A rejected step ends the run. Repeatable
+#   --approve-timeout <dur>[:approve]
+#                         Reject (or approve) when nobody answers in time;
+#                         by default the run waits indefinitely
+#   --job <label=cmd>     Run these shell commands side by side instead of a
+#                         command after --, e.g. 'shard1=python sweep.py 1';
+#                         repeatable. Output lines are prefixed "[label] ",
+#                         each failure sends its own ❌ alert, progress
+#                         reports from the jobs add up to one ("3/8 jobs
+#                         done, slowest at 40%") that drives --milestones,
+#                         and the final message lists every job. Not with
+#                         --step
+#   --watch-file <path>   Follow a log written by a job started elsewhere
+#                         (SLURM, nohup, k8s) instead of running a command:
+#                         the file is read from the start and followed
+#                         across rotation and truncation, as with tail -F,
+#                         and its lines get the same parsing, milestones and
+#                         alerts as command output
+#   --until <regex>       End the watch with success at the first line
+#                         matching this ERE (e.g. 'Done|Finished')
+#   --watch-pid <pid>     End the watch when this process exits; without
+#                         --until the run counts as completed, with it as
+#                         failed unless the pattern was seen. Otherwise the
+#                         watch lasts until stopped
+#   --milestones <list>   Progress percentages that send a 📈 message with the
+#                         elapsed time and metrics (default 25,50,75; "none"
+#                         turns them off). See the progress protocol below
+#   --alert-on <regex>    Send an instant 🚨 alert when an output line matches
+#                         (awk ERE, e.g. 'CUDA out of memory|NaN loss');
+#                         repeatable. Each pattern alerts at most once per 5
+#                         minutes; later matches are counted in the next one.
+#                         A "stderr:" prefix only matches stderr lines and
+#                         turns on --split-streams
+#   --level-spike [regex] Count warning/error lines (awk ERE, default WARN,
+#                         WARNING, ERROR, FATAL or CRITICAL as a word) per
+#                         minute and send a 📣 alert when a minute has at
+#                         least 10 and 5x the run's average so far (after 5
+#                         minutes); it re-arms once the rate drops back
+#   --notify-start        Send a ▶️ "started" message with the host, command
+#                         and run id as soon as the child is running, so
+#                         long runs have an anchor message
+#   --live-gist           Keep the last 200 output lines in a secret GitHub
+#                         gist (via gh), refreshed every minute; its link is
+#                         sent in a "started" message and in the final one
+#   --attach-tail <size>  On failure or timeout, attach the last <size> of
+#                         output (e.g. 64K) as a log file; it takes the
+#                         place of --plot on those messages
+#   --sample-lines <1/N>  Parse only every Nth output line (plus lines
+#                         matching --sample-keep or --alert-on, and
+#                         ::ocnotify:: reports) for progress, metrics and
+#                         alerts, for ultra-verbose jobs. The log, stdout
+#                         and message tails still get every line
+#   --sample-keep <regex> Lines always kept when sampling (awk ERE, matched
+#                         case-insensitively; default: error/warning words)
+#   --dedupe-lines        Collapse repeated identical lines in the log
+#   --stall-after <dur>   Warn when the child prints nothing for this long
+#                         (e.g. 900, 15m, 1h30m) and again when it resumes
+#   --heartbeat [dur]     Until the child reports any progress, send a 💓
+#                         message with its latest output line after dur
+#                         (default 5m), then after gaps three times as long
+#                         each time (15m, 45m, ...) up to 4h, each ±10%
+#   --timeout <dur>       Send SIGTERM to the child after this long and report
+#                         the run as timed out
+#   --retries <n>         Run the child again, up to n more times, when it
+#                         fails or times out; a 🔁 message announces each new
+#                         attempt and the final message lists them all
+#   --retry-backoff <dur> Wait before the first retry (default 10s), doubled
+#                         for each further one
+#   --retry-on <codes|regex>
+#                         Only retry on these exit codes (e.g. 1,75,143) or
+#                         when the last 200 output lines match this ERE
+#                         (e.g. 'Connection reset|ECONNREFUSED')
+#   --service             The child is a server that should never exit: any
+#                         exit is reported as a 💥 failure and, with
+#                         --retries, restarts it (backoff starts over after
+#                         10 minutes up); 3 restarts within 10 minutes send
+#                         one critical "crash-looping" alert
+#   --ready-on <regex|[host]:port>
+#                         Send a 🟢 message once the child is ready: when an
+#                         output line matches the ERE, or when the port
+#                         accepts connections on the job host (polled every
+#                         5s); again after each restart
+#   --probe <url|[host]:port>[:every]
+#                         Check an HTTP endpoint (curl, 2xx/3xx) or a TCP
+#                         port on the job host every 30s (or e.g. :10s) and
+#                         send a 🩺 alert after 3 failures in a row, once
+#                         the probe has passed at least once, and again when
+#                         it recovers
+#   --kill-after <dur>    Grace period before SIGKILL follows (default 30s);
+#                         also used when the wrapper itself is interrupted
+#                         (Ctrl-C, SIGTERM, SIGHUP) and forwards the signal.
+#                         Signals reach the child's whole process group,
+#                         except when stdin is a terminal: the child then
+#                         keeps the terminal for password prompts (sudo,
+#                         ssh, git) and only the child itself is signalled
+#   --pty                 Run the child on a pseudo-terminal so tools that
+#                         only draw progress bars on a tty (tqdm, pip, cargo)
+#                         still do. Uses script(1) locally and ssh -tt for
+#                         remote jobs. Run from a terminal, the pty takes its
+#                         size and follows resizes (SIGWINCH), and bars are
+#                         redrawn in place there since stdout passes through
+#                         unchanged
+#   --split-streams       Keep stderr apart from stdout (they are still
+#                         interleaved in the log): tails of failed, timed
+#                         out and interrupted runs show stderr when the job
+#                         wrote any. Not with --pty, which merges them
+#   --tail-buffer <size>  Recent output kept for message tails and cause
+#                         matching, with or without --log (default 1M; the
+#                         buffer holds between one and two times this)
+#   --io-stats            Sample disk IO of the child's processes (Linux
+#                         /proc/<pid>/io, every 10s) and report read/written
+#                         totals and average rates in the final message
+#   --reap-orphans        Stop background processes the child left running
+#                         (TERM, then KILL after 5s); without it they are
+#                         only listed in the final message (Linux job hosts)
+#   --keep-awake          Keep this machine from sleeping while the run lasts
+#                         (caffeinate on macOS, systemd-inhibit on Linux);
+#                         the final message says so if that failed
+#   --local-only          Refuse to start if anything besides the sinks named
+#                         with --sink would send data off this machine (no
+#                         default sink, no implied webhook sink, no
+#                         --live-gist)
+#   --audit-log <path>    Append a JSON line per message sent off this machine
+#                         (time, sink, destination, delivered, the text as
+#                         sent and any attachment); also OCNOTIFY_AUDIT_LOG
+#   --tee-fd <fd|path>    Also copy the raw merged output, before
+#                         --dedupe-lines, to an inherited fd (e.g. 3 with
+#                         3>&1) or a FIFO/file, for composing with ts, tee or
+#                         custom loggers. A FIFO blocks until it has a reader.
+#   --template-<event> <text>
+#                         Replace the default text of an event (done/completed,
+#                         milestone/progress, failed, interrupted, budget,
+#                         preempt, ...). The first line becomes the title;
+#                         "\n" starts a new line. Placeholders: {label}
+#                         {host} {event} {exit} {elapsed} {start} {end} {log}
+#                         {cost} {percent} {progress} {current} {total} {eta},
+#                         and {metric.NAME} or {metric.NAME:FMT}
+#                         for the latest --metric value (FMT like .3f, d or
+#                         .1% for a percentage), {delta.NAME} for its trend.
+#                         Templates can also be set as OCNOTIFY_TEMPLATE_<EVENT>
+#                         in the environment or config file, e.g.
+#                         OCNOTIFY_TEMPLATE_DONE='{label} ok in {elapsed}'
+#
+# The email sink is configured through the environment or the config file
+# (${XDG_CONFIG_HOME:-~/.config}/ocnotify/config, sourced as bash):
+#   OCNOTIFY_EMAIL_TO     Comma-separated recipients (required)
+#   OCNOTIFY_EMAIL_FROM   Sender address (default: ocnotify@<hostname>)
+#   OCNOTIFY_SMTP_URL     e.g. smtps://smtp.example.com:465; without it the
+#                         message is handed to the local sendmail
+#   OCNOTIFY_SMTP_USER / OCNOTIFY_SMTP_PASS   SMTP credentials
+#
+# The ntfy sink reads OCNOTIFY_NTFY_TOPIC (same as --ntfy-topic),
+# OCNOTIFY_NTFY_SERVER (default https://ntfy.sh) and OCNOTIFY_NTFY_TOKEN.
+# Events are published at the --ntfy-priority level of their severity or
+# name: by default critical ones (failures, timeouts, interruptions, alerts)
+# at high, milestones and heartbeats at min, everything else at default.
+#
+# OCNOTIFY_NTFY_TOKEN, OCNOTIFY_SMTP_PASS and --webhook may name a secret
+# instead of holding it: "keyring:NAME" reads the system keyring (stored with
+# `secret-tool store --label=ocnotify service ocnotify account NAME`, or
+# `security add-generic-password -s ocnotify -a NAME -w` on macOS), and
+# "age:FILE" decrypts FILE with age and ~/.config/ocnotify/age.key.
+# Short-lived tokens can come from a command run at send time instead:
+# OCNOTIFY_NTFY_TOKEN_CMD / OCNOTIFY_SMTP_PASS_CMD, e.g. "pass show smtp".
+#
+# Progress protocol: a child line of the form
+#   ::ocnotify::{"percent": 42, "summary": "epoch 3/8", "loss": 0.31}
+# is taken as a progress report and left out of tails and metric matching.
+# Known keys are percent, current and total (percent is derived from these
+# when missing) and summary; any other numeric key is recorded as a metric,
+# and a nested object such as "metrics": {...} is flattened. Messages after
+# a report show "42% (3/8): epoch 3/8". An "artifact" key (a path) is listed
+# in the final message, and a "phase" key moves the run to that phase (see
+# --phases; undeclared names are added). An "approve" key (a name) asks for
+# approval like --approve-before, and the answer is written to
+# $OCNOTIFY_DECISION_FILE on the job host ("approve" or "reject"); use
+# oc-notify-emit.sh approve <name>, which waits for it. Reports over 64 KiB
+# or nested more than 32 levels deep are dropped (with one warning on
+# stderr), and keys and values are cut to 1024 characters.
+#
+# The child gets OCNOTIFY_RUN_ID, OCNOTIFY_LABEL, OCNOTIFY_PROGRESS_FIFO (a
+# FIFO that takes the same JSON reports, one per line, without the prefix)
+# OCNOTIFY_DECISION_FILE and, for local runs with --log, OCNOTIFY_LOG_FILE.
+#
+# Editor extensions and status bars can find active runs through
+# $OCNOTIFY_RUNTIME_DIR/<run id>/status.json (under $XDG_RUNTIME_DIR by
+# default), rewritten whenever the state or progress changes and removed
+# when the run ends: {"version": 1, "run_id", "label", "host", "pid",
+# "command", "cwd", "tty", "start", "start_epoch", "state" (running or
+# stalled), "attempt", "percent", "current", "total", "summary", "phase",
+# "progress" (the text messages show), "log", "updated"}; unknown values are
+# null.
+#
+# Failure messages get a "Likely cause" line when the output tail matches a
+# rule in oc-notify-causes.tsv (or ~/.config/ocnotify/causes.tsv, checked
+# first), e.g. CUDA OOM, missing modules, disk full, NCCL timeouts.
+
+# mapfile -d, associative arrays and printf '%(...)T' need bash 4.4; macOS
+# still ships 3.2 as /bin/bash.
+if (( BASH_VERSINFO[0] < 4 || (BASH_VERSINFO[0] == 4 && BASH_VERSINFO[1] < 4) )); then
+  echo "ocnotify needs bash 4.4 or newer, this is ${BASH_VERSION} (on macOS: brew install bash)" >&2
+  exit 2
+fi
+
+OCNOTIFY_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+source "${OCNOTIFY_DIR}/oc-notify-sinks.sh"
+
+OCNOTIFY_CONFIG="${OCNOTIFY_CONFIG:-${XDG_CONFIG_HOME:-$HOME/.config}/ocnotify/config}"
+if [[ -f "$OCNOTIFY_CONFIG" ]]; then
+  # shellcheck source=/dev/null
+  source "$OCNOTIFY_CONFIG"
+fi
+
+CHANNEL=""
+TARGET=""
+SINK_NAMES=(openclaw)
+SINK_FILTERS=(all)
+SINKS_GIVEN=""
+NOTIFY_FILTER=""
+MIN_DURATION=""
+MIN_DURATION_ALL=""
+OPENCLAW_FORMAT=""
+OPENCLAW_PRIORITY=""
+NTFY_PRIORITY=""
+declare -A TEMPLATES=()
+LOG_PATH=""
+COST_RATE=""
+MAX_COST=""
+WEBHOOK_URL=""
+TAIL_LINES=20
+WATCH_PREEMPTION=""
+ON_PREEMPT=""
+ON_SUCCESS=""
+ON_FAILURE=""
+HOOK_TIMEOUT=60
+HOOK_RESULTS=""
+MILESTONES="25,50,75"
+PARSER="auto"
+PARSE_RULE_RES=()
+PARSER_CMD=""
+PARSER_CMD_EVERY=10
+PARSER_TIMEOUT=10
+PARSE_RULE_NAMES=()
+PARSE_RULE_SUMMARIES=()
+PROGRESS_FILE=""
+PROGRESS_FILE_SIZE=""
+PROGRESS_GLOB=""
+PROGRESS_GLOB_COUNT=""
+PHASES=()
+PHASE_RES=()
+PHASE_WORD="Phase"
+STEPS=()
+KEEP_GOING=""
+APPROVE_BEFORE=()
+APPROVE_TIMEOUT=""
+APPROVE_ON_TIMEOUT="reject"
+APPROVALS=()
+DECISION_FILE=""
+JOBS=()
+WATCH_FILE=""
+WATCH_UNTIL=""
+WATCH_PID=""
+JOB_LABELS=()
+declare -A JOB_INDEX=() JOB_PERCENT=() JOB_EXIT=() JOB_END=()
+declare -A PROGRESS_WEIGHTS=()
+declare -A SOURCE_PERCENT=() SOURCE_SUMMARY=() SOURCE_CURRENT=() SOURCE_TOTAL=()
+PROGRESS_SOURCE_ORDER=()
+PROGRESS_POLICY="weighted"
+PROGRESS_SMOOTHING="raw"
+PROGRESS_HALF_LIFE=120
+PROGRESS_POLL_SEC=10
+PROGRESS_WINDOW=600
+# Limits on ::ocnotify:: reports, which come from the child unchecked.
+PROGRESS_MAX_BYTES=65536
+PROGRESS_MAX_DEPTH=32
+JSON_FIELD_MAX=1024
+PARSERS=""
+PREEMPT_POLL_SEC=15
+WATCH_BATTERY=""
+BATTERY_POLL_SEC=30
+PLOT_PATH=""
+SAMPLE_EVERY=""
+SAMPLE_KEEP="error|exception|traceback|fatal|panic|fail|warn|killed|nan"
+DEDUPE_LINES=""
+TEE_TARGET=""
+STALL_AFTER=""
+HEARTBEAT=""
+HEARTBEAT_FACTOR=3
+HEARTBEAT_MAX=14400
+TIMEOUT=""
+KILL_AFTER=30
+RETRIES=0
+RETRY_BACKOFF=10
+RETRY_CODES=""
+RETRY_RE=""
+SH_CMD=""
+SH_STATUS=""
+CGROUP=""
+MEMORY_MAX=""
+PIPE_STDIN=""
+SERVICE=""
+SERVICE_STABLE_SEC=600
+SERVICE_LOOP_WINDOW=600
+SERVICE_LOOP_RESTARTS=3
+READY_RE=""
+READY_ADDR=""
+READY_POLL_SEC=5
+PROBE_TARGET=""
+PROBE_EVERY=30
+PROBE_FAILURES=3
+CGROUP_STATS=""
+REAP_ORPHANS=""
+ORPHANS=""
+INTERRUPTED=""
+BACKOFF_PID=""
+IO_STATS=""
+IO_POLL_SEC=10
+PTY=""
+TAIL_BUFFER=1048576
+LOG_TIMESTAMPS=""
+LOG_MAX_SIZE=""
+LOG_KEEP=3
+ATTACH_TAIL=""
+METRIC_NAMES=()
+METRIC_REGEXES=()
+METRICS_FILE=""
+PLOT_METRICS=""
+declare -A METRIC_GOALS=()
+ALERT_PATTERNS=()
+ALERT_STREAMS=()
+SPLIT_STREAMS=""
+LEVEL_RE=""
+LEVEL_WINDOW=60
+LEVEL_WARMUP=5
+LEVEL_SPIKE_MIN=10
+LEVEL_SPIKE_FACTOR=5
+THRESHOLDS=()
+# Per --alert rule: "" (always), seconds, or a progress percent like "50%".
+THRESHOLD_AFTER=()
+BEST_METRICS=()
+BEST_COOLDOWN=600
+HISTORY_METRICS=()
+HISTORY_BASELINE=5
+REGRESSION_TOLERANCE=5
+PROFILE=1
+HOST_CONTEXT_ON=1
+PROFILE_KEEP=20
+EXPECTED_SEC=""
+AUDIT_LOG="${OCNOTIFY_AUDIT_LOG:-}"
+LOCAL_ONLY=""
+KEEP_AWAKE=""
+KEEP_AWAKE_FAILED=""
+OCNOTIFY_STATE_DIR="${OCNOTIFY_STATE_DIR:-${XDG_STATE_HOME:-$HOME/.local/state}/ocnotify}"
+ALERT_COOLDOWN=300
+LIVE_GIST=""
+NOTIFY_START=""
+LIVE_GIST_EVERY=60
+GIST_ID=""
+GIST_URL=""
+declare -A IO_READ=() IO_WRITE=()
+# Exported to the child only; anything still carrying it after the child
+# exits was started by the run.
+RUN_ID="$(hostname)-$$-${RANDOM}"
+# mawk reads a pipe a block at a time unless run with -W interactive; the
+# output stages need each line as soon as it arrives.
+STREAM_AWK=(awk)
+if awk -W version 2>&1 | grep -q mawk; then
+  STREAM_AWK=(awk -W interactive)
+fi
+# Set by the SSH wrapper, which sets up the child's context on the remote side.
+REMOTE_CHILD=""
+# Per-run state shared with oc-notify-ctl.sh (run info, mute/snooze).
+OCNOTIFY_RUNTIME_DIR="${OCNOTIFY_RUNTIME_DIR:-${XDG_RUNTIME_DIR:-${TMPDIR:-/tmp}}/ocnotify}"
+RUN_DIR=""
+
+# Prints the pending preemption notice and exits 0 if the instance is about
+# to be reclaimed (EC2 spot instance-action, GCE preempted flag).
+PREEMPT_PROBE='
+md=http://169.254.169.254/latest
+tok=$(curl -fsS -m 2 -X PUT -H "X-aws-ec2-metadata-token-ttl-seconds: 60" "$md/api/token" 2>/dev/null) &&
+  curl -fsS -m 2 -H "X-aws-ec2-metadata-token: $tok" "$md/meta-data/spot/instance-action" 2>/dev/null && exit 0
+gce=$(curl -fsS -m 2 -H "Metadata-Flavor: Google" \
+  http://metadata.google.internal/computeMetadata/v1/instance/preempted 2>/dev/null)
+[ "$gce" = TRUE ] && echo "GCE instance preempted" && exit 0
+exit 1'
+
+# Prints a one-line summary of how busy the host is: load, memory (and
+# PSI memory pressure), the top CPU users, disks over 50% busy during a
+# one-second sample and filesystems over 90% full. Linux only; prints
+# nothing elsewhere.
+HOST_CONTEXT="$(cat <<'EOF'
+[ -r /proc/loadavg ] || exit 0
+parts=()
+read -r l1 l5 l15 _ </proc/loadavg
+parts+=("load ${l1}/${l5}/${l15} on $(nproc 2>/dev/null || echo '?') CPUs")
+parts+=("$(awk '/^MemTotal:/ { t = $2 } /^MemAvailable:/ { a = $2 } /^SwapTotal:/ { st = $2 } /^SwapFree:/ { sf = $2 }
+  END { printf "mem %.1fG free of %.1fG", a / 1048576, t / 1048576; if (st - sf > 0) printf ", swap %.1fG used", (st - sf) / 1048576 }' /proc/meminfo)")
+if [ -r /proc/pressure/memory ]; then
+  psi="$(awk '/^some/ { sub("avg10=", "", $2); if ($2 + 0 >= 1) print $2 }' /proc/pressure/memory)"
+  [ -z "$psi" ] || parts+=("memory pressure ${psi}%")
+fi
+top="$(ps -eo pcpu=,comm= --sort=-pcpu 2>/dev/null | awk 'NR <= 3 && $1 + 0 >= 5 { printf "%s%s %d%%", (NR > 1 ? ", " : ""), $2, $1 }')"
+[ -z "$top" ] || parts+=("top CPU ${top}")
+disks() { awk '$3 ~ /^(sd[a-z]+|nvme[0-9]+n[0-9]+|vd[a-z]+|xvd[a-z]+)$/ { print $3, $13 }' /proc/diskstats; }
+before="$(disks)"
+sleep 1
+busy="$(disks | awk 'NR == FNR { t[$1] = $2; next }
+  { u = ($2 - t[$1]) / 10; if (u >= 50) printf "%s%s %d%% busy", (s++ ? ", " : ""), $1, (u > 100 ? 100 : u) }' <(printf '%s\n' "$before") -)"
+[ -z "$busy" ] || parts+=("$busy")
+full="$(df -P 2>/dev/null | awk 'NR > 1 && $5 + 0 >= 90 { printf "%s%s %s full", (s++ ? ", " : ""), $6, $5 }')"
+[ -z "$full" ] || parts+=("$full")
+out="${parts[0]}"
+for p in "${parts[@]:1}"; do out+=" · $p"; done
+printf '%s\n' "$out"
+EOF
+)"
+
+# Parses one shared option from "$@". Sets OPT_SHIFT to the number of args
+# consumed; returns 1 if the option is not a common one.
+parse_common_opt() {
+  OPT_SHIFT=2
+  case "$1" in
+    --label)
+      LABEL="${2:-}" ;;
+    --channel)
+      CHANNEL="${2:-}" ;;
+    --target)
+      TARGET="${2:-}" ;;
+    --sink)
+      if [[ -z "$SINKS_GIVEN" ]]; then
+        SINKS_GIVEN=1
+        SINK_NAMES=()
+        SINK_FILTERS=()
+      fi
+      local spec
+      for spec in ${2//,/ }; do
+        add_sink "$spec"
+      done ;;
+    --notify)
+      case "${2:-}" in
+        all) NOTIFY_FILTER="" ;;
+        on-done) NOTIFY_FILTER="final" ;;
+        on-failure) NOTIFY_FILTER="failure" ;;
+        *) echo "Invalid --notify: ${2:-} (expected all, on-done or on-failure)" >&2; exit 2 ;;
+      esac ;;
+    --min-duration)
+      MIN_DURATION_ALL=""
+      if [[ "${2:-}" == *:all ]]; then
+        MIN_DURATION_ALL=1
+      fi
+      MIN_DURATION="$(parse_duration "${2%:all}")" || exit 2 ;;
+    --log|--log-file)
+      LOG_PATH="${2:-}" ;;
+    --log-timestamps)
+      LOG_TIMESTAMPS=1; OPT_SHIFT=1 ;;
+    --log-max-size)
+      LOG_MAX_SIZE="$(parse_size "${2:-}")" || exit 2 ;;
+    --cost-rate)
+      COST_RATE="$(parse_cost_rate "${2:-}")" || exit 2 ;;
+    --max-cost)
+      MAX_COST="${2:-}"
+      if ! is_number "$MAX_COST"; then
+        echo "Invalid --max-cost: $MAX_COST" >&2; exit 2
+      fi ;;
+    --webhook)
+      WEBHOOK_URL="${2:-}" ;;
+    --ntfy-topic)
+      OCNOTIFY_NTFY_TOPIC="${2:-}" ;;
+    --openclaw-priority)
+      OPENCLAW_PRIORITY="${2:-}"
+      if ! [[ "$OPENCLAW_PRIORITY" =~ ^([a-z_]+=(high|normal|low|silent),?)+$ ]]; then
+        echo "Invalid --openclaw-priority: $OPENCLAW_PRIORITY" >&2; exit 2
+      fi ;;
+    --ntfy-priority)
+      NTFY_PRIORITY="${2:-}"
+      if ! [[ "$NTFY_PRIORITY" =~ ^([a-z_]+=(high|normal|low|silent),?)+$ ]]; then
+        echo "Invalid --ntfy-priority: $NTFY_PRIORITY" >&2; exit 2
+      fi ;;
+    --openclaw-format)
+      OPENCLAW_FORMAT="${2:-}"
+      case "$OPENCLAW_FORMAT" in
+        markdown|slack|plain) ;;
+        *) echo "Invalid --openclaw-format: $OPENCLAW_FORMAT" >&2; exit 2 ;;
+      esac ;;
+    --watch-preemption)
+      WATCH_PREEMPTION=1; OPT_SHIFT=1 ;;
+    --on-preempt)
+      ON_PREEMPT="${2:-}"; WATCH_PREEMPTION=1 ;;
+    --watch-battery)
+      WATCH_BATTERY=20
+      OPT_SHIFT=1
+      if is_number "${2:-}"; then
+        WATCH_BATTERY="$2"
+        OPT_SHIFT=2
+      fi ;;
+    --on-success)
+      ON_SUCCESS="${2:-}" ;;
+    --on-failure)
+      ON_FAILURE="${2:-}" ;;
+    --hook-timeout)
+      HOOK_TIMEOUT="$(parse_duration "${2:-}")" || exit 2 ;;
+    --plot)
+      PLOT_PATH="${2:-}" ;;
+    --metric)
+      add_metric "${2:-}" ;;
+    --metrics-file)
+      METRICS_FILE="${2:-}" ;;
+    --metric-goal)
+      local goal
+      for goal in ${2//,/ }; do
+        if ! [[ "$goal" =~ ^([A-Za-z_][A-Za-z0-9_.-]*)=(min|max)$ ]]; then
+          echo "Invalid --metric-goal: $goal (expected name=min or name=max)" >&2; exit 2
+        fi
+        METRIC_GOALS[${BASH_REMATCH[1]}]="${BASH_REMATCH[2]}"
+      done ;;
+    --best)
+      BEST_METRICS+=("${2:-}") ;;
+    --history)
+      HISTORY_METRICS+=("${2:-}") ;;
+    --regression-tolerance)
+      REGRESSION_TOLERANCE="${2:-}"
+      if ! [[ "$REGRESSION_TOLERANCE" =~ ^[0-9]+([.][0-9]+)?$ ]]; then
+        echo "Invalid --regression-tolerance: ${REGRESSION_TOLERANCE} (expected a percentage, e.g. 5)" >&2; exit 2
+      fi ;;
+    --plot-metrics)
+      PLOT_METRICS=1; OPT_SHIFT=1 ;;
+    --no-profile)
+      PROFILE=""; OPT_SHIFT=1 ;;
+    --no-host-context)
+      HOST_CONTEXT_ON=""; OPT_SHIFT=1 ;;
+    --expected)
+      EXPECTED_SEC="$(parse_duration "${2:-}")" || exit 2 ;;
+    --alert)
+      if ! [[ "${2:-}" =~ ^[[:space:]]*[A-Za-z_][A-Za-z0-9_.-]*[[:space:]]*(\<=?|\>=?|==|!=)[[:space:]]*-?[0-9.]+([eE][+-]?[0-9]+)?([[:space:]]+after[[:space:]]+([0-9dhms]+|[0-9.]+%))?[[:space:]]*$ ]]; then
+        echo "Invalid --alert: ${2:-} (e.g. 'loss > 10', 'acc < 0.5 after 2h' or 'after 50%')" >&2; exit 2
+      fi
+      local after="${BASH_REMATCH[4]}"
+      if [[ -n "$after" && "$after" != *% ]]; then
+        after="$(parse_duration "$after")" || exit 2
+      fi
+      THRESHOLDS+=("$2")
+      THRESHOLD_AFTER+=("$after") ;;
+    --alert-on)
+      if [[ "${2:-}" == stderr:* ]]; then
+        ALERT_PATTERNS+=("${2#stderr:}")
+        ALERT_STREAMS+=(stderr)
+        SPLIT_STREAMS=1
+      else
+        ALERT_PATTERNS+=("${2:-}")
+        ALERT_STREAMS+=(any)
+      fi ;;
+    --split-streams)
+      SPLIT_STREAMS=1; OPT_SHIFT=1 ;;
+    --level-spike)
+      LEVEL_RE='(^|[^A-Za-z])(WARN|WARNING|ERROR|FATAL|CRITICAL)([^A-Za-z]|$)'
+      OPT_SHIFT=1
+      if [[ -n "${2:-}" && "$2" != -* ]]; then
+        LEVEL_RE="$2"
+        OPT_SHIFT=2
+      fi ;;
+    --parser)
+      PARSER="${2:-}"
+      local profile
+      for profile in ${PARSER//,/ }; do
+        if [[ " auto none ${PARSER_PROFILES} " != *" ${profile} "* ]]; then
+          echo "Unknown --parser: ${profile} (one of: auto none ${PARSER_PROFILES})" >&2; exit 2
+        fi
+      done
+      if [[ "$PARSER" == none ]]; then
+        PARSER=""
+      fi ;;
+    --parse-rule)
+      add_parse_rule "${2:-}" ;;
+    --parser-cmd)
+      PARSER_CMD="${2:-}" ;;
+    --parser-timeout)
+      PARSER_TIMEOUT="$(parse_duration "${2:-}")" || exit 2 ;;
+    --progress-from-file)
+      if ! [[ "${2:-}" =~ ^(.+):([^:]+)$ ]]; then
+        echo "Invalid --progress-from-file: ${2:-} (expected path:size, e.g. out.bin:120G)" >&2; exit 2
+      fi
+      PROGRESS_FILE="${BASH_REMATCH[1]}"
+      PROGRESS_FILE_SIZE="$(parse_size "${BASH_REMATCH[2]}")" || exit 2 ;;
+    --progress-from-count)
+      if ! [[ "${2:-}" =~ ^(.+):([0-9]+)$ ]] || (( BASH_REMATCH[2] == 0 )); then
+        echo "Invalid --progress-from-count: ${2:-} (expected 'glob':count, e.g. 'frames/*.png':14400)" >&2; exit 2
+      fi
+      PROGRESS_GLOB="${BASH_REMATCH[1]}"
+      PROGRESS_GLOB_COUNT="${BASH_REMATCH[2]}" ;;
+    --phases)
+      PHASES=()
+      PHASE_RES=()
+      local phase
+      for phase in ${2//,/ }; do
+        if [[ "$phase" == *=* ]]; then
+          PHASES+=("${phase%%=*}")
+          PHASE_RES+=("${phase#*=}")
+        else
+          PHASES+=("$phase")
+          PHASE_RES+=("$(phase_word_re "$phase")")
+        fi
+      done ;;
+    --step)
+      STEPS+=("${2:-}") ;;
+    --keep-going)
+      KEEP_GOING=1; OPT_SHIFT=1 ;;
+    --watch-file)
+      WATCH_FILE="${2:-}" ;;
+    --until)
+      WATCH_UNTIL="${2:-}" ;;
+    --watch-pid)
+      if ! [[ "${2:-}" =~ ^[0-9]+$ ]]; then
+        echo "Invalid --watch-pid: ${2:-}" >&2; exit 2
+      fi
+      WATCH_PID="$2" ;;
+    --approve-before)
+      APPROVE_BEFORE+=("${2:-}") ;;
+    --approve-timeout)
+      APPROVE_ON_TIMEOUT="reject"
+      if [[ "${2:-}" == *:approve ]]; then
+        APPROVE_ON_TIMEOUT="approve"
+      fi
+      APPROVE_TIMEOUT="$(parse_duration "${2%:approve}")" || exit 2 ;;
+    --job)
+      if ! [[ "${2:-}" =~ ^[A-Za-z0-9_.-]+=. ]]; then
+        echo "Invalid --job: ${2:-} (expected label=command)" >&2; exit 2
+      fi
+      JOBS+=("$2")
+      JOB_INDEX[${2%%=*}]="${#JOB_LABELS[@]}"
+      JOB_LABELS+=("${2%%=*}") ;;
+    --progress-sources)
+      PROGRESS_WEIGHTS=()
+      PROGRESS_SOURCE_ORDER=()
+      local source
+      for source in ${2//,/ }; do
+        if ! [[ "$source" =~ ^(output|file|count)(=([0-9]+([.][0-9]+)?))?$ ]]; then
+          echo "Invalid --progress-sources entry: ${source} (expected output, file or count, optionally =weight)" >&2; exit 2
+        fi
+        PROGRESS_WEIGHTS[${BASH_REMATCH[1]}]="${BASH_REMATCH[3]:-1}"
+        PROGRESS_SOURCE_ORDER+=("${BASH_REMATCH[1]}")
+      done ;;
+    --progress-policy)
+      PROGRESS_POLICY="${2:-}"
+      if ! [[ "$PROGRESS_POLICY" =~ ^(weighted|first|max)$ ]]; then
+        echo "Invalid --progress-policy: ${PROGRESS_POLICY} (expected weighted, first or max)" >&2; exit 2
+      fi ;;
+    --progress-smoothing)
+      PROGRESS_SMOOTHING="${2:-}"
+      if ! [[ "$PROGRESS_SMOOTHING" =~ ^(raw|monotonic|ewma)$ ]]; then
+        echo "Invalid --progress-smoothing: ${PROGRESS_SMOOTHING} (expected raw, monotonic or ewma)" >&2; exit 2
+      fi ;;
+    --milestones)
+      MILESTONES="${2:-}"
+      if [[ "$MILESTONES" == none ]]; then
+        MILESTONES=""
+      elif ! [[ "$MILESTONES" =~ ^[0-9]+(,[0-9]+)*$ ]]; then
+        echo "Invalid --milestones: $MILESTONES (e.g. 25,50,75 or none)" >&2; exit 2
+      fi ;;
+    --live-gist)
+      LIVE_GIST=1; OPT_SHIFT=1 ;;
+    --notify-start)
+      NOTIFY_START=1; OPT_SHIFT=1 ;;
+    --attach-tail)
+      ATTACH_TAIL="$(parse_size "${2:-}")" || exit 2 ;;
+    --sample-lines)
+      SAMPLE_EVERY="${2:-}"
+      SAMPLE_EVERY="${SAMPLE_EVERY#1/}"
+      if ! [[ "$SAMPLE_EVERY" =~ ^[1-9][0-9]*$ ]]; then
+        echo "Invalid --sample-lines: ${2:-} (expected 1/N)" >&2; exit 2
+      fi ;;
+    --sample-keep)
+      SAMPLE_KEEP="${2:-}" ;;
+    --dedupe-lines)
+      DEDUPE_LINES=1; OPT_SHIFT=1 ;;
+    --heartbeat)
+      HEARTBEAT=300
+      OPT_SHIFT=1
+      if [[ "${2:-}" =~ ^[0-9][0-9dhms]*$ ]]; then
+        HEARTBEAT="$(parse_duration "$2")" || exit 2
+        OPT_SHIFT=2
+      fi ;;
+    --stall-after)
+      STALL_AFTER="$(parse_duration "${2:-}")" || exit 2 ;;
+    --timeout)
+      TIMEOUT="$(parse_duration "${2:-}")" || exit 2 ;;
+    --kill-after)
+      KILL_AFTER="$(parse_duration "${2:-}")" || exit 2 ;;
+    --retries)
+      RETRIES="${2:-}"
+      if ! [[ "$RETRIES" =~ ^[0-9]+$ ]]; then
+        echo "Invalid --retries: ${RETRIES}" >&2; exit 2
+      fi ;;
+    --service)
+      SERVICE=1; OPT_SHIFT=1 ;;
+    --ready-on)
+      if [[ "${2:-}" =~ ^([A-Za-z0-9.-]*):([0-9]+)$ ]]; then
+        READY_ADDR="${BASH_REMATCH[1]:-127.0.0.1}/${BASH_REMATCH[2]}"
+      else
+        READY_RE="${2:-}"
+      fi ;;
+    --probe)
+      PROBE_TARGET="${2:-}"
+      PROBE_EVERY=30
+      if [[ "$PROBE_TARGET" =~ ^(.+):([0-9]+[dhms][0-9dhms]*)$ ]]; then
+        PROBE_TARGET="${BASH_REMATCH[1]}"
+        PROBE_EVERY="$(parse_duration "${BASH_REMATCH[2]}")" || exit 2
+      fi
+      if ! [[ "$PROBE_TARGET" =~ ^https?:// || "$PROBE_TARGET" =~ ^[A-Za-z0-9.-]*:[0-9]+$ ]]; then
+        echo "Invalid --probe: ${2:-} (e.g. http://localhost:8080/health:30s or :5432)" >&2; exit 2
+      fi ;;
+    --retry-backoff)
+      RETRY_BACKOFF="$(parse_duration "${2:-}")" || exit 2 ;;
+    --retry-on)
+      if [[ "${2:-}" =~ ^[0-9]+(,[0-9]+)*$ ]]; then
+        RETRY_CODES=",${2},"
+        RETRY_RE=""
+      else
+        RETRY_RE="${2:-}"
+        RETRY_CODES=""
+      fi ;;
+    --reap-orphans)
+      REAP_ORPHANS=1; OPT_SHIFT=1 ;;
+    --audit-log)
+      AUDIT_LOG="${2:-}" ;;
+    --local-only)
+      LOCAL_ONLY=1; OPT_SHIFT=1 ;;
+    --keep-awake)
+      KEEP_AWAKE=1; OPT_SHIFT=1 ;;
+    --io-stats)
+      IO_STATS=1; OPT_SHIFT=1 ;;
+    --pty)
+      PTY=1; OPT_SHIFT=1 ;;
+    --tail-buffer)
+      TAIL_BUFFER="$(parse_size "${2:-}")" || exit 2 ;;
+    --tee-fd)
+      TEE_TARGET="${2:-}"
+      if [[ "$TEE_TARGET" =~ ^[0-9]+$ ]]; then
+        TEE_TARGET="/dev/fd/${TEE_TARGET}"
+      fi ;;
+    --template-done)
+      TEMPLATES[completed]="${2:-}" ;;
+    --template-milestone)
+      TEMPLATES[progress]="${2:-}" ;;
+    --template-?*)
+      if ! event_known "${1#--template-}"; then
+        echo "Unknown event for $1 (e.g. --template-done, --template-failed)" >&2; exit 2
+      fi
+      TEMPLATES[${1#--template-}]="${2:-}" ;;
+    *)
+      return 1 ;;
+  esac
+}
+
+is_number() {
+  [[ "$1" =~ ^[0-9]+([.][0-9]+)?$ ]]
+}
+
+# "90" -> 90, "15m" -> 900, "1h30m" -> 5400 (seconds).
+parse_duration() {
+  local rest="$1" total=0 n unit
+  if [[ "$rest" =~ ^[0-9]+$ ]]; then
+    printf '%s' "$rest"
+    return
+  fi
+  if ! [[ "$rest" =~ ^([0-9]+[dhms])+$ ]]; then
+    echo "Invalid duration: $1 (e.g. 90, 15m, 1h30m)" >&2; return 1
+  fi
+  while [[ "$rest" =~ ^([0-9]+)([dhms])(.*)$ ]]; do
+    n="${BASH_REMATCH[1]}" unit="${BASH_REMATCH[2]}" rest="${BASH_REMATCH[3]}"
+    case "$unit" in
+      d) total=$(( total + n * 86400 )) ;;
+      h) total=$(( total + n * 3600 )) ;;
+      m) total=$(( total + n * 60 )) ;;
+      s) total=$(( total + n )) ;;
+    esac
+  done
+  printf '%s' "$total"
+}
+
+# "512" -> 512, "64K" -> 65536, "1.5M" -> 1572864 (bytes).
+parse_size() {
+  if ! [[ "$1" =~ ^([0-9]+([.][0-9]+)?)([KMGT]?)i?B?$ ]]; then
+    echo "Invalid size: $1 (e.g. 512K, 4M)" >&2; return 1
+  fi
+  awk -v n="${BASH_REMATCH[1]}" -v u="${BASH_REMATCH[3]}" \
+    'BEGIN { printf "%d", n * (u == "K" ? 1024 : u == "M" ? 1048576 : u == "G" ? 1073741824 : u == "T" ? 1099511627776 : 1) }'
+}
+
+# Turns a value with units into a plain number: "1.2k" -> 1200, "3.4M
+# samples" -> 3400000, "2.5GiB" -> 2684354560, "1h23m" and "1:23:00" -> 4980
+# (seconds), "250ms" -> 0.25, "45%" -> 45, "1,234,567" -> 1234567. K/M/G/T/P
+# are powers of 1000, Ki/Mi/... of 1024. Anything else is printed unchanged.
+normalize_number() {
+  if [[ "$1" =~ ^-?[0-9]+([.][0-9]+)?([eE][+-]?[0-9]+)?$ ]]; then
+    printf '%s' "$1"
+    return
+  fi
+  awk -v v="$1" "${NORMALIZE_AWK}"' BEGIN { print normalize(v) }'
+}
+
+# awk function normalize(v) behind normalize_number, for stages that
+# normalise many values in one long-lived awk.
+NORMALIZE_AWK='
+  function normalize(v,  n, part, i, secs, u, scale) {
+    if (v ~ /^-?[0-9]+([.][0-9]+)?([eE][+-]?[0-9]+)?$/) return v
+    gsub(/^[ \t]+|[ \t]+$/, "", v)
+    if (v ~ /^[0-9][0-9]?[0-9]?(,[0-9][0-9][0-9])+([.][0-9]+)?$/) {
+      gsub(/,/, "", v)
+      return v
+    }
+    if (v ~ /^[0-9]+(:[0-5][0-9])+([.][0-9]+)?$/) {
+      n = split(v, part, ":")
+      for (i = 1; i <= n; i++) secs = secs * 60 + part[i]
+      return sprintf("%.15g", secs)
+    }
+    if (v ~ /^([0-9]+([.][0-9]+)?(ms|d|h|m|s))+$/) {
+      while (match(v, /^[0-9]+([.][0-9]+)?/)) {
+        n = substr(v, 1, RLENGTH) + 0
+        v = substr(v, RLENGTH + 1)
+        if (v ~ /^ms/) {
+          secs += n / 1000
+          v = substr(v, 3)
+        } else {
+          u = substr(v, 1, 1)
+          secs += n * (u == "d" ? 86400 : (u == "h" ? 3600 : (u == "m" ? 60 : 1)))
+          v = substr(v, 2)
+        }
+      }
+      return sprintf("%.15g", secs)
+    }
+    if (match(v, /^-?[0-9]+([.][0-9]+)?([eE][+-]?[0-9]+)?/)) {
+      n = substr(v, 1, RLENGTH) + 0
+      u = substr(v, RLENGTH + 1)
+      sub(/^ +/, "", u)
+      scale = 1
+      # A prefix counts only before a unit or the end ("12 GPUs" stays 12).
+      if (u ~ /^[kKMGTP]i/) {
+        scale = 1024 ^ index("KMGTP", toupper(substr(u, 1, 1)))
+      } else if (u ~ /^[kKMGTP]([Bb\/ ]|$)/) {
+        scale = 1000 ^ index("KMGTP", toupper(substr(u, 1, 1)))
+      }
+      return sprintf("%.15g", n * scale)
+    }
+    return v
+  }'
+
+# awk json_str(s) and json_number(s), as in oc-notify-sinks.sh.
+JSON_AWK='
+  function json_str(s) {
+    gsub(/\\/, "&&", s); gsub(/"/, "\\\"", s)
+    gsub(/\t/, "\\t", s); gsub(/\r/, "\\r", s)
+    gsub(/[\001-\010\013\014\016-\037]/, "", s)
+    return "\"" s "\""
+  }
+  function json_number(s) {
+    if (s ~ /^-?(0|[1-9][0-9]*)([.][0-9]+)?([eE][+-]?[0-9]+)?$/) return s
+    return json_str(s)
+  }'
+
+# Registers a --metric NAME=REGEX, translating \s \d \w for bash's ERE.
+add_metric() {
+  local name="${1%%=*}" re="${1#*=}"
+  if [[ "$1" != *=* || ! "$name" =~ ^[A-Za-z_][A-Za-z0-9_.-]*$ || -z "$re" ]]; then
+    echo "Invalid --metric: $1 (expected name=regex)" >&2; exit 2
+  fi
+  re="${re//\\s/[[:space:]]}"
+  re="${re//\\d/[0-9]}"
+  re="${re//\\w/[[:alnum:]_]}"
+  METRIC_NAMES+=("$name")
+  METRIC_REGEXES+=("$re")
+}
+
+# An awk ERE matching a word that starts with $1, in any case: "test"
+# becomes (^|[^A-Za-z0-9_])[tT][eE][sS][tT] and matches "Testing".
+phase_word_re() {
+  local word="$1" re="" c i
+  for (( i = 0; i < ${#word}; i++ )); do
+    c="${word:i:1}"
+    if [[ "$c" =~ [A-Za-z] ]]; then
+      re+="[${c,}${c^}]"
+    else
+      re+="[${c}]"
+    fi
+  done
+  printf '(^|[^A-Za-z0-9_])%s' "$re"
+}
+
+# Adds a --parse-rule: an ERE (plus \d \s \w) whose named groups, written
+# (?P<name>...) or (?<name>...), fill a progress report, optionally followed
+# by " => " and a summary template with {name} placeholders.
+add_parse_rule() {
+  local rule="$1" template="" out="" names="" rest c i=0 group=0
+  if [[ "$rule" == *" => "* ]]; then
+    template="${rule##* => }"
+    rule="${rule% => *}"
+  fi
+  while (( i < ${#rule} )); do
+    c="${rule:i:1}"
+    rest="${rule:i}"
+    if [[ "$c" == \\ ]]; then
+      out+="${rule:i:2}"
+      i=$(( i + 2 ))
+    elif [[ "$rest" =~ ^\[\^?\]?[^]]*\] ]]; then
+      # Parentheses in a bracket expression are not groups.
+      out+="${BASH_REMATCH[0]}"
+      i=$(( i + ${#BASH_REMATCH[0]} ))
+    elif [[ "$rest" =~ ^\(\?P?\<([A-Za-z_][A-Za-z0-9_]*)\> ]]; then
+      group=$(( group + 1 ))
+      names+=" ${group}:${BASH_REMATCH[1]}"
+      out+="("
+      i=$(( i + ${#BASH_REMATCH[0]} ))
+    elif [[ "$rest" == "(?:"* ]]; then
+      # ERE has no non-capturing groups; it just becomes one more group.
+      group=$(( group + 1 ))
+      out+="("
+      i=$(( i + 3 ))
+    else
+      if [[ "$c" == "(" ]]; then
+        group=$(( group + 1 ))
+      fi
+      out+="$c"
+      i=$(( i + 1 ))
+    fi
+  done
+  if [[ -z "$names" ]]; then
+    echo "Invalid parse rule (no named groups): $1" >&2; exit 2
+  fi
+  out="${out//\\s/[[:space:]]}"
+  out="${out//\\d/[0-9]}"
+  out="${out//\\w/[[:alnum:]_]}"
+  PARSE_RULE_RES+=("$out")
+  PARSE_RULE_NAMES+=("${names# }")
+  PARSE_RULE_SUMMARIES+=("$template")
+}
+
+# "3.50/h" -> 3.50, "0.06/m" -> 3.6, "3.5" -> 3.5 (per hour).
+parse_cost_rate() {
+  local amount="${1%/*}" unit="h"
+  if [[ "$1" == */* ]]; then
+    unit="${1##*/}"
+  fi
+  if ! is_number "$amount"; then
+    echo "Invalid --cost-rate: $1" >&2; return 1
+  fi
+  case "$unit" in
+    h|hr|hour) awk -v a="$amount" 'BEGIN { print a }' ;;
+    m|min) awk -v a="$amount" 'BEGIN { print a * 60 }' ;;
+    s|sec) awk -v a="$amount" 'BEGIN { print a * 3600 }' ;;
+    *) echo "Invalid --cost-rate unit: $unit (use /h, /m or /s)" >&2; return 1 ;;
+  esac
+}
+
+require_common_opts() {
+  local rule
+  for rule in "${OCNOTIFY_PARSE_RULES[@]}"; do
+    add_parse_rule "$rule"
+  done
+  if (( ${#STEPS[@]} == 0 )); then
+    STEPS=("${OCNOTIFY_STEPS[@]}")
+  fi
+  if (( ${#STEPS[@]} > 0 )); then
+    if (( ${#PHASES[@]} > 0 )); then
+      echo "--step and --phases are mutually exclusive" >&2
+      exit 2
+    fi
+    local step
+    for step in "${STEPS[@]}"; do
+      PHASES+=("$(step_name "$step")")
+    done
+    PHASE_WORD="Step"
+  fi
+  if (( ${#APPROVE_BEFORE[@]} > 0 && ${#STEPS[@]} == 0 )); then
+    echo "--approve-before needs --step (other commands can ask with oc-notify-emit.sh approve)" >&2
+    exit 2
+  fi
+  if (( ${#JOBS[@]} > 0 && ${#STEPS[@]} > 0 )); then
+    echo "--job and --step are mutually exclusive" >&2
+    exit 2
+  fi
+  if [[ -n "$WATCH_FILE" ]] && (( ${#JOBS[@]} > 0 || ${#STEPS[@]} > 0 )); then
+    echo "--watch-file and --step/--job are mutually exclusive" >&2
+    exit 2
+  fi
+  if [[ -z "$WATCH_FILE" && -n "${WATCH_UNTIL}${WATCH_PID}" ]]; then
+    echo "--until and --watch-pid need --watch-file" >&2
+    exit 2
+  fi
+  if [[ -n "$LOCAL_ONLY" ]]; then
+    local offending=()
+    if [[ -z "$SINKS_GIVEN" ]]; then
+      offending+=("the default openclaw sink (name the sinks with --sink)")
+    fi
+    if [[ -n "$WEBHOOK_URL" ]] && ! has_sink webhook; then
+      offending+=("--webhook without --sink webhook")
+    fi
+    if [[ -n "$LIVE_GIST" ]]; then
+      offending+=("--live-gist (uploads output to GitHub)")
+    fi
+    if (( ${#offending[@]} > 0 )); then
+      echo "--local-only: would send data elsewhere through:" >&2
+      printf '  %s\n' "${offending[@]}" >&2
+      exit 2
+    fi
+  fi
+  if [[ -n "$LIVE_GIST" ]] && ! command -v gh >/dev/null 2>&1; then
+    echo "--live-gist needs the GitHub CLI (gh), logged in" >&2
+    exit 2
+  fi
+  if [[ -n "$SPLIT_STREAMS" && -n "$PTY" ]]; then
+    echo "--split-streams (or a stderr: --alert-on pattern) does not work with --pty" >&2
+    exit 2
+  fi
+  if [[ -n "$ATTACH_TAIL" ]] && (( ATTACH_TAIL > TAIL_BUFFER )); then
+    TAIL_BUFFER="$ATTACH_TAIL"
+  fi
+  if [[ -n "$WEBHOOK_URL" ]] && ! has_sink webhook; then
+    add_sink webhook
+  fi
+  if has_sink webhook && [[ -z "$WEBHOOK_URL" ]]; then
+    echo "The webhook sink needs --webhook" >&2
+    exit 2
+  fi
+  if has_sink openclaw && [[ -z "$CHANNEL" || -z "$TARGET" ]]; then
+    echo "--channel and --target are required" >&2
+    exit 2
+  fi
+  if has_sink ntfy && [[ -z "${OCNOTIFY_NTFY_TOPIC:-}" ]]; then
+    echo "The ntfy sink needs --ntfy-topic or OCNOTIFY_NTFY_TOPIC" >&2
+    exit 2
+  fi
+  if has_sink email; then
+    if [[ -z "${OCNOTIFY_EMAIL_TO:-}" ]]; then
+      echo "The email sink needs OCNOTIFY_EMAIL_TO" >&2
+      exit 2
+    fi
+    if [[ -z "${OCNOTIFY_SMTP_URL:-}" ]] && ! command -v sendmail >/dev/null 2>&1; then
+      echo "The email sink needs OCNOTIFY_SMTP_URL or a local sendmail" >&2
+      exit 2
+    fi
+  fi
+}
+
+# Runs a shell command on the host where the job runs. The SSH wrapper
+# overrides this to go through ssh. The wrapper's stdin belongs to the child,
+# so probes never read it.
+host_sh() {
+  bash -c "$1" </dev/null
+}
+
+# Prints a local path for a file on the job host (the SSH wrapper copies it
+# into RUN_TMP first). Fails if the file is not there.
+host_fetch() {
+  [[ -f "$1" ]] && printf '%s' "$1"
+}
+
+# Removes files the run left on the job host. The SSH wrapper overrides this.
+cleanup_host() {
+  :
+}
+
+# Sends signal $1 to the child, or to its whole process group when it was
+# started with setsid (CHILD_PGROUP="-"; not for a child run from a
+# terminal). The SSH wrapper overrides this to signal the remote process
+# instead of the local ssh client.
+stop_child() {
+  kill -s "$1" -- "${CHILD_PGROUP}${CHILD_PID}" 2>/dev/null
+}
+
+now_iso() {
+  date -u +%Y-%m-%dT%H:%M:%SZ
+}
+
+# 75 -> "1m15s", 7260 -> "2h01m".
+fmt_duration() {
+  local s="$1"
+  if (( s >= 3600 )); then
+    printf '%dh%02dm' $(( s / 3600 )) $(( s % 3600 / 60 ))
+  elif (( s >= 60 )); then
+    printf '%dm%02ds' $(( s / 60 )) $(( s % 60 ))
+  else
+    printf '%ds' "$s"
+  fi
+}
+
+# 1536 -> "1.5 KiB", 3435973837 -> "3.2 GiB".
+fmt_bytes() {
+  awk -v b="$1" 'BEGIN {
+    split("B KiB MiB GiB TiB", u, " ")
+    for (i = 1; b >= 1024 && i < 5; i++) b /= 1024
+    printf (i == 1 ? "%d %s" : "%.1f %s"), b, u[i]
+  }'
+}
+
+# Accumulated cost for ELAPSED seconds at COST_RATE per hour.
+cost_so_far() {
+  awk -v r="$COST_RATE" -v s="$1" 'BEGIN { printf "%.2f", r * s / 3600 }'
+}
+
+# Last TAIL_LINES lines of the run's output (from the tail buffer), without
+# colour and cursor escape codes. Repeated lines are collapsed so retry
+# storms don't crowd out the context.
+output_tail() {
+  local buffer="${1:-recent}"
+  cat "${RUN_TMP}/${buffer}.old" "${RUN_TMP}/${buffer}" 2>/dev/null | tail -n $(( TAIL_LINES * 100 )) \
+    | strip_ansi | collapse_repeats | tail -n "$TAIL_LINES" || true
+}
+
+# Removes ANSI escape sequences (CSI colour/cursor codes, OSC titles and
+# charset switches) from stdin.
+strip_ansi() {
+  awk '{
+    gsub(/\033\[[0-9;?]*[ -\/]*[@-~]/, "")
+    gsub(/\033\][^\007\033]*(\007|\033\\)/, "")
+    gsub(/\033[()][0-9A-Za-z]/, "")
+    print
+  }'
+}
+
+# Replaces runs of identical lines with the line plus a
+# "[last line repeated N times]" marker.
+collapse_repeats() {
+  awk '
+    function flush() { if (n > 0) printf "[last line repeated %d time%s]\n", n, (n > 1 ? "s" : ""); n = 0 }
+    NR > 1 && $0 == prev { n++; next }
+    { flush(); print; fflush(); prev = $0 }
+    END { flush() }'
+}
+
+# Called roughly once per second while the child is alive.
+monitor_tick() {
+  local elapsed=$(( $(date +%s) - start_epoch ))
+  if [[ -n "$COST_RATE" && -n "$MAX_COST" && -z "$budget_alerted" ]]; then
+    local cost
+    cost="$(cost_so_far "$elapsed")"
+    if awk -v c="$cost" -v m="$MAX_COST" 'BEGIN { exit !(c > m) }'; then
+      budget_alerted=1
+      event_begin budget "💸 ${LABEL}${HOST_SUFFIX} passed cost budget (still running)"
+      event_field Cost "~\$${cost} > \$${MAX_COST}"
+      notify
+    fi
+  fi
+  if [[ -n "$WATCH_PREEMPTION" && -z "$preempt_seen" ]] \
+      && (( elapsed >= next_preempt_check )); then
+    next_preempt_check=$(( elapsed + PREEMPT_POLL_SEC ))
+    check_preemption
+  fi
+  if [[ -n "$WATCH_BATTERY" ]] && (( elapsed >= next_battery_check )); then
+    next_battery_check=$(( elapsed + BATTERY_POLL_SEC ))
+    check_battery
+  fi
+  if [[ -n "$IO_STATS" ]] && (( elapsed >= next_io_sample )); then
+    next_io_sample=$(( elapsed + IO_POLL_SEC ))
+    sample_io
+  fi
+  if [[ -n "$GIST_ID" ]] && (( elapsed >= next_gist_update )); then
+    next_gist_update=$(( elapsed + LIVE_GIST_EVERY ))
+    update_live_gist
+  fi
+  if [[ -n "$PARSER_CMD" ]] && (( elapsed >= next_parser_run )); then
+    next_parser_run=$(( elapsed + PARSER_CMD_EVERY ))
+    run_parser_cmd
+  fi
+  if [[ -s "${RUN_TMP}/progress" ]]; then
+    check_progress
+  fi
+  if [[ -n "$PROGRESS_FILE" ]] && (( elapsed >= next_progress_poll )); then
+    next_progress_poll=$(( elapsed + PROGRESS_POLL_SEC ))
+    check_file_progress
+  fi
+  if [[ -n "$PROGRESS_GLOB" ]] && (( elapsed >= next_count_poll )); then
+    next_count_poll=$(( elapsed + PROGRESS_POLL_SEC ))
+    check_count_progress
+  fi
+  if (( ${#ALERT_PATTERNS[@]} > 0 )); then
+    check_alerts
+  fi
+  if [[ -n "${READY_RE}${READY_ADDR}" && -z "$ready_sent" ]]; then
+    check_ready "$elapsed"
+  fi
+  if [[ -n "$PROBE_TARGET" ]] && (( elapsed >= next_probe )); then
+    next_probe=$(( elapsed + PROBE_EVERY ))
+    check_probe
+  fi
+  if [[ -n "$LEVEL_RE" ]] && (( elapsed >= next_level_check )); then
+    next_level_check=$(( elapsed + LEVEL_WINDOW ))
+    check_level_rate
+  fi
+  if (( ${#THRESHOLDS[@]} > 0 )); then
+    check_thresholds
+  fi
+  if (( ${#BEST_METRICS[@]} > 0 )); then
+    check_best
+  fi
+  if [[ -s "${RUN_DIR}/notes" ]]; then
+    check_notes
+  fi
+  if [[ -n "$OVERDUE_AFTER" ]]; then
+    check_overdue
+  fi
+  if [[ -n "$APPROVAL_PENDING" ]]; then
+    check_approval
+  elif [[ -n "$STALL_AFTER" ]]; then
+    check_stall
+  fi
+  if [[ -n "$HEARTBEAT" && -n "$next_heartbeat" ]] && (( elapsed >= next_heartbeat )); then
+    check_heartbeat "$elapsed"
+  fi
+  if [[ -n "$TIMEOUT" ]]; then
+    check_timeout "$elapsed"
+  fi
+}
+
+# TERM at the deadline, KILL once the grace period has passed as well.
+check_timeout() {
+  local elapsed="$1"
+  if [[ -z "$timed_out" ]] && (( elapsed >= TIMEOUT )); then
+    timed_out=1
+    stop_child TERM || true
+  elif [[ -n "$timed_out" && -z "$kill_sent" ]] && (( elapsed >= TIMEOUT + KILL_AFTER )); then
+    kill_sent=1
+    stop_child KILL || true
+    reap_child
+  fi
+}
+
+# Waits for the child once and keeps its exit status in CHILD_RC. Reaping
+# with stderr silenced keeps bash's "Killed" job report for a SIGKILLed
+# child out of the wrapper's output.
+reap_child() {
+  if [[ -z "$CHILD_RC" ]]; then
+    local rc=0
+    wait "$CHILD_PID" 2>/dev/null || rc=$?
+    CHILD_RC="$rc"
+  fi
+}
+
+# Whether EVENT is muted or snoozed for this run (see oc-notify-ctl.sh).
+# RUN_DIR/silence holds "<until epoch, 0 = no end> <filter>" lines; final
+# events always go out.
+event_silenced() {
+  local f="${RUN_DIR}/silence" now until filter
+  if [[ -z "$RUN_DIR" || ! -s "$f" ]] || event_matches final "$1"; then
+    return 1
+  fi
+  printf -v now '%(%s)T' -1
+  while read -r until filter; do
+    if [[ ! "$until" =~ ^[0-9]+$ ]]; then
+      continue
+    fi
+    if (( until == 0 || now < until )) && event_matches "$filter" "$1"; then
+      return 0
+    fi
+  done <"$f"
+  return 1
+}
+
+# Evaluates the --alert rules against the latest metric values.
+check_thresholds() {
+  local csv="${RUN_TMP}/metrics.csv" size
+  [[ -s "$csv" ]] || return 0
+  local elapsed=$(( $(date +%s) - start_epoch ))
+  size="$(wc -c <"$csv")"
+  # Unchanged values are rechecked every 10s for rules with "after" (and
+  # right away when new progress came in).
+  if (( size == thresholds_checked && elapsed < thresholds_recheck )) \
+      && [[ "$PROGRESS_PERCENT" == "$thresholds_percent" ]]; then
+    return 0
+  fi
+  thresholds_checked="$size"
+  thresholds_percent="$PROGRESS_PERCENT"
+  thresholds_recheck=$(( elapsed + 10 ))
+  local i rule name op limit after value
+  for i in "${!THRESHOLDS[@]}"; do
+    rule="${THRESHOLDS[$i]}"
+    [[ "$rule" =~ ^[[:space:]]*([A-Za-z_][A-Za-z0-9_.-]*)[[:space:]]*([<>=!]=?)[[:space:]]*([^[:space:]]+) ]]
+    name="${BASH_REMATCH[1]}" op="${BASH_REMATCH[2]}" limit="${BASH_REMATCH[3]}" after="${THRESHOLD_AFTER[$i]}"
+    if [[ "$after" == *% ]]; then
+      if [[ -z "$PROGRESS_PERCENT" ]] \
+          || ! awk -v p="$PROGRESS_PERCENT" -v a="${after%\%}" 'BEGIN { exit !(p >= a) }'; then
+        continue
+      fi
+    elif [[ -n "$after" ]] && (( elapsed < after )); then
+      continue
+    fi
+    value="$(metric_value "$name")" || continue
+    if awk -v v="$value" -v l="$limit" -v op="$op" 'BEGIN {
+        if (v !~ /^-?[0-9.]+([eE][+-]?[0-9]+)?$/) exit 1
+        v += 0; l += 0
+        exit !(op == ">" ? v > l : op == ">=" ? v >= l : op == "<" ? v < l : op == "<=" ? v <= l : op == "==" ? v == l : v != l)
+      }'; then
+      if [[ -z "${THRESHOLD_FIRED[$i]:-}" ]]; then
+        THRESHOLD_FIRED[$i]=1
+        event_begin threshold "⚠️ ${LABEL}${HOST_SUFFIX}: ${name} = ${value} (alert: ${rule# })"
+        event_field Elapsed "$(fmt_duration "$elapsed")"
+        notify
+      fi
+    else
+      THRESHOLD_FIRED[$i]=""
+    fi
+  done
+}
+
+# History file with "<metric> <best value> <when>" lines for this label.
+best_file() {
+  printf '%s/best/%s.tsv' "$OCNOTIFY_STATE_DIR" "${LABEL//[^A-Za-z0-9._-]/_}"
+}
+
+# Best recorded value of metric NAME in this run, by its --metric-goal.
+run_best() {
+  awk -F, -v m="$1" -v goal="${METRIC_GOALS[$1]:-max}" '
+    $3 == m && $4 ~ /^-?[0-9.]+([eE][+-]?[0-9]+)?$/ {
+      if (!found || (goal == "min" ? $4 + 0 < best : $4 + 0 > best)) best = $4 + 0
+      found = 1
+    }
+    END { if (!found) exit 1; print best }' "${RUN_TMP}/metrics.csv" 2>/dev/null
+}
+
+# Whether A beats B for metric NAME (B empty: nothing to beat).
+beats() {
+  awk -v a="$2" -v b="$3" -v goal="${METRIC_GOALS[$1]:-max}" \
+    'BEGIN { exit !(b == "" || (goal == "min" ? a + 0 < b + 0 : a + 0 > b + 0)) }'
+}
+
+# Durations of recent successful runs with this label, as "<end> <seconds>"
+# lines.
+duration_file() {
+  printf '%s/durations/%s.tsv' "$OCNOTIFY_STATE_DIR" "${LABEL//[^A-Za-z0-9._-]/_}"
+}
+
+# Sets TYPICAL_SEC (median), TYPICAL_RUNS and OVERDUE_AFTER from the label's
+# duration history; all stay empty with fewer than 3 runs.
+load_profile() {
+  TYPICAL_SEC="" TYPICAL_RUNS="" OVERDUE_AFTER=""
+  [[ -n "$PROFILE" && -s "$(duration_file)" ]] || return 0
+  local stats
+  stats="$(awk '{ d[NR] = $2 + 0; if ($2 + 0 > max) max = $2 + 0 }
+    END {
+      if (NR < 3) exit
+      for (i = 2; i <= NR; i++) for (j = i; j > 1 && d[j - 1] > d[j]; j--) { t = d[j]; d[j] = d[j - 1]; d[j - 1] = t }
+      median = NR % 2 ? d[(NR + 1) / 2] : (d[NR / 2] + d[NR / 2 + 1]) / 2
+      print int(median), NR, int(max > median * 1.5 ? max : median * 1.5)
+    }' "$(duration_file)")"
+  if [[ -n "$stats" ]]; then
+    read -r TYPICAL_SEC TYPICAL_RUNS OVERDUE_AFTER <<<"$stats"
+  fi
+}
+
+# Adds this run's duration to the label history, keeping the last
+# PROFILE_KEEP.
+save_profile() {
+  local file
+  file="$(duration_file)"
+  mkdir -p "$(dirname "$file")"
+  { tail -n $(( PROFILE_KEEP - 1 )) "$file" 2>/dev/null || true; echo "${end_iso} $(( end_epoch - start_epoch ))"; } \
+    >"${file}.tmp" && mv -f "${file}.tmp" "$file"
+}
+
+check_overdue() {
+  local elapsed=$(( $(date +%s) - start_epoch ))
+  if [[ -z "$overdue_sent" ]] && (( elapsed > OVERDUE_AFTER )); then
+    overdue_sent=1
+    event_begin overdue "⏳ ${LABEL}${HOST_SUFFIX} has been running for $(fmt_duration "$elapsed"), longer than usual"
+    event_field Typical "$(fmt_duration "$TYPICAL_SEC") (median of the last ${TYPICAL_RUNS} successful runs)"
+    notify
+  fi
+}
+
+# Loads the all-time bests for the --best metrics from the label history.
+load_best() {
+  local name value when
+  BEST_ALLTIME=()
+  BEST_WHEN=()
+  BEST_SENT=()
+  if [[ -f "$(best_file)" ]]; then
+    while read -r name value when; do
+      BEST_ALLTIME[$name]="$value"
+      BEST_WHEN[$name]="$when"
+    done <"$(best_file)"
+  fi
+}
+
+# Sends "🏆 new best" when a --best metric beats the all-time best (and the
+# last such message for it is BEST_COOLDOWN old).
+check_best() {
+  local csv="${RUN_TMP}/metrics.csv" size name best previous now
+  [[ -s "$csv" ]] || return 0
+  size="$(wc -c <"$csv")"
+  if (( size == best_checked )); then
+    return 0
+  fi
+  best_checked="$size"
+  now="$(date +%s)"
+  for name in "${BEST_METRICS[@]}"; do
+    best="$(run_best "$name")" || continue
+    previous="${BEST_ALLTIME[$name]:-}"
+    if [[ -z "$previous" ]] || ! beats "$name" "$best" "$previous" \
+        || (( now < ${BEST_SENT[$name]:-0} + BEST_COOLDOWN )); then
+      continue
+    fi
+    BEST_SENT[$name]="$now"
+    event_begin best "🏆 ${LABEL}${HOST_SUFFIX}: new best ${name} ${best}"
+    event_field Previous "${previous} (${BEST_WHEN[$name]:-earlier run})"
+    notify
+  done
+}
+
+# "val_acc=0.931 (new best, was 0.924)" for each --best metric, and saves
+# new bests to the label history. Fails if there is nothing to report.
+best_summary() {
+  local name best previous out="" file
+  file="$(best_file)"
+  for name in "${BEST_METRICS[@]}"; do
+    best="$(run_best "$name")" || continue
+    previous="${BEST_ALLTIME[$name]:-}"
+    if beats "$name" "$best" "$previous"; then
+      out+="${out:+, }${name}=${best} (new best${previous:+, was ${previous}})"
+      mkdir -p "$(dirname "$file")"
+      { grep -v "^${name} " "$file" 2>/dev/null || true; echo "${name} ${best} ${end_iso:-$(now_iso)}"; } \
+        >"${file}.tmp" && mv -f "${file}.tmp" "$file"
+    else
+      out+="${out:+, }${name}=${best} (best ever ${previous})"
+    fi
+  done
+  [[ -n "$out" ]] && printf '%s' "$out"
+}
+
+# History file with "<metric> <value> <when>" lines, one per completed run,
+# for this label.
+history_file() {
+  printf '%s/history/%s.tsv' "$OCNOTIFY_STATE_DIR" "${LABEL//[^A-Za-z0-9._-]/_}"
+}
+
+# Whether metric $1 matches a --history pattern.
+history_tracked() {
+  local pattern
+  for pattern in "${HISTORY_METRICS[@]}"; do
+    [[ "$1" == $pattern ]] && return 0
+  done
+  return 1
+}
+
+# Compares the last value of each --history metric with the median of its
+# HISTORY_BASELINE previous runs, sends a 📉 warning for those worse by more
+# than REGRESSION_TOLERANCE percent and adds the values to the label
+# history (the last PROFILE_KEEP per metric). Sets HISTORY_TEXT to e.g.
+# "coverage=81.2 (↓1.8 ✗ vs median 83 of 5 runs), bench.fib=2.6e-05 (first
+# run)".
+check_history() {
+  local file name value goal compare text regressed when
+  HISTORY_TEXT=""
+  [[ -s "${RUN_TMP}/metrics.csv" ]] || return 0
+  file="$(history_file)"
+  when="${end_iso:-$(now_iso)}"
+  mkdir -p "$(dirname "$file")"
+  touch "$file"
+  while read -r name; do
+    history_tracked "$name" || continue
+    value="$(metric_value "$name")" && is_number "$value" || continue
+    goal="${METRIC_GOALS[$name]:-max}"
+    if [[ "$name" == bench.* && -z "${METRIC_GOALS[$name]:-}" ]]; then
+      goal="min"
+    fi
+    compare="$(awk -v m="$name" -v v="$value" -v goal="$goal" -v keep="$HISTORY_BASELINE" -v tol="$REGRESSION_TOLERANCE" '
+      $1 == m { h[++n] = $2 + 0 }
+      END {
+        if (n == 0) { print "0 first run"; exit }
+        k = n < keep ? n : keep
+        for (i = 1; i <= k; i++) x[i] = h[n - k + i]
+        for (i = 2; i <= k; i++) for (j = i; j > 1 && x[j - 1] > x[j]; j--) { t = x[j]; x[j] = x[j - 1]; x[j - 1] = t }
+        med = k % 2 ? x[(k + 1) / 2] : (x[k / 2] + x[k / 2 + 1]) / 2
+        d = v - med
+        worse = (goal == "min" ? d > 0 : d < 0)
+        rel = (med != 0 ? (d < 0 ? -d : d) / (med < 0 ? -med : med) * 100 : (d != 0 ? 100 : 0))
+        printf "%d ", (worse && rel > tol)
+        if (d == 0) printf "="
+        else printf "%s%g %s", (d > 0 ? "↑" : "↓"), (d > 0 ? d : -d), (worse ? "✗" : "✓")
+        printf " vs median %g of %d run%s", med, k, (k == 1 ? "" : "s")
+      }' "$file")"
+    regressed="${compare%% *}"
+    text="${name}=${value} (${compare#* })"
+    HISTORY_TEXT+="${HISTORY_TEXT:+, }${text}"
+    if [[ "$regressed" == 1 ]]; then
+      event_begin regression "📉 ${LABEL}${HOST_SUFFIX}: ${name} regressed to ${value}"
+      event_field History "${text}, tolerance ${REGRESSION_TOLERANCE}%"
+      notify
+    fi
+    echo "${name} ${value} ${when}" >>"$file"
+  done < <(awk -F, '!seen[$3]++ { print $3 }' "${RUN_TMP}/metrics.csv")
+  awk -v keep="$PROFILE_KEEP" '{ line[NR] = $0; name[NR] = $1; count[$1]++ }
+    END { for (i = 1; i <= NR; i++) if (seen[name[i]]++ >= count[name[i]] - keep) print line[i] }' \
+    "$file" >"${file}.tmp" && mv -f "${file}.tmp" "$file"
+}
+
+# Sends an alert for each new --alert-on match, at most one per pattern per
+# ALERT_COOLDOWN; matches in between are counted and mentioned in the next.
+check_alerts() {
+  if [[ ! -s "${RUN_TMP}/alerts" ]]; then
+    return 0
+  fi
+  local now i line
+  printf -v now '%(%s)T' -1
+  while read -r i line; do
+    alerts_seen=$(( alerts_seen + 1 ))
+    if (( now < ${ALERT_NEXT[$i]:-0} )); then
+      ALERT_MISSED[$i]=$(( ${ALERT_MISSED[$i]:-0} + 1 ))
+      continue
+    fi
+    ALERT_NEXT[$i]=$(( now + ALERT_COOLDOWN ))
+    local source=output
+    if [[ "${ALERT_STREAMS[$i]}" == stderr ]]; then
+      source=stderr
+    fi
+    event_begin alert "🚨 ${LABEL}${HOST_SUFFIX}: ${source} matched /${ALERT_PATTERNS[$i]}/"
+    event_field Line "$line"
+    if (( ${ALERT_MISSED[$i]:-0} > 0 )); then
+      event_field "Also" "${ALERT_MISSED[$i]} earlier match(es) not sent separately"
+      ALERT_MISSED[$i]=0
+    fi
+    notify
+  done < <(tail -n +$(( alerts_seen + 1 )) "${RUN_TMP}/alerts")
+}
+
+# Sends the notes added with `oc-notify-ctl.sh <run> note --post` since the
+# last tick. RUN_DIR/notes holds "<epoch> <post|keep> <text>" lines.
+check_notes() {
+  local when post text
+  while read -r when post text; do
+    notes_seen=$(( notes_seen + 1 ))
+    if [[ "$post" == post ]]; then
+      event_begin note "📝 ${LABEL}${HOST_SUFFIX}: ${text}"
+      event_field Elapsed "$(fmt_duration $(( when - start_epoch )))"
+      notify
+    fi
+  done < <(tail -n +$(( notes_seen + 1 )) "${RUN_DIR}/notes")
+}
+
+# "+1h02m switched to AdamW; +3h10m ..." for the notes added to this run.
+notes_summary() {
+  local when post text out=""
+  [[ -s "${RUN_DIR}/notes" ]] || return 1
+  while read -r when post text; do
+    out+="${out:+; }+$(fmt_duration $(( when - start_epoch ))) ${text}"
+  done <"${RUN_DIR}/notes"
+  printf '%s' "$out"
+}
+
+# Reads "<epoch> <last line>" written by watch_stage; before any output the
+# run start counts as the last activity.
+read_activity() {
+  last_activity="$start_epoch"
+  last_line=""
+  if [[ -s "${RUN_TMP}/activity" ]]; then
+    local entry
+    entry="$(<"${RUN_TMP}/activity")"
+    last_activity="${entry%% *}"
+    last_line="$(strip_ansi <<<"${entry#* }")"
+  fi
+}
+
+# Asks for approval before $1 (a --approve-before step, or a child's
+# "approve" report); check_approval waits for the answer.
+request_approval() {
+  APPROVAL_PENDING="$1"
+  approval_since="$(date +%s)"
+  rm -f "${RUN_DIR}/approval"
+  event_begin approval "⏸️ ${LABEL}${HOST_SUFFIX}: waiting for approval before ${1}"
+  local progress
+  if progress="$(progress_text)"; then
+    event_field Progress "$progress"
+  fi
+  event_field Reply "approve or reject (oc-notify-ctl.sh \"${LABEL}\" approve)"
+  if [[ -n "$APPROVE_TIMEOUT" ]]; then
+    event_field Timeout "${APPROVE_ON_TIMEOUT} after $(fmt_duration "$APPROVE_TIMEOUT")"
+  fi
+  notify
+}
+
+# Passes an answer from oc-notify-ctl.sh (RUN_DIR/approval), or the
+# --approve-timeout default once it is due, on to the child.
+check_approval() {
+  local decision="" how="" waited=$(( $(date +%s) - approval_since ))
+  if [[ -s "${RUN_DIR}/approval" ]]; then
+    decision="$(<"${RUN_DIR}/approval")"
+  elif [[ -n "$APPROVE_TIMEOUT" ]] && (( waited >= APPROVE_TIMEOUT )); then
+    decision="$APPROVE_ON_TIMEOUT"
+    how=" (no answer after $(fmt_duration "$waited"))"
+  fi
+  case "$decision" in
+    "") return 0 ;;
+    approve) ;;
+    reject|deny) decision=reject ;;
+    *)
+      # Only oc-notify-ctl.sh writes this file; ignore anything else.
+      rm -f "${RUN_DIR}/approval"
+      return 0 ;;
+  esac
+  host_sh "printf '%s\n' $(printf '%q' "$decision") >$(printf '%q' "$DECISION_FILE")" || true
+  if [[ "$decision" == approve ]]; then
+    APPROVALS+=("${APPROVAL_PENDING} approved${how}")
+    event_begin approved "✅ ${LABEL}${HOST_SUFFIX}: ${APPROVAL_PENDING} approved${how}"
+  else
+    APPROVALS+=("${APPROVAL_PENDING} rejected${how}")
+    event_begin rejected "⛔ ${LABEL}${HOST_SUFFIX}: ${APPROVAL_PENDING} rejected${how}"
+  fi
+  notify
+  APPROVAL_PENDING=""
+  rm -f "${RUN_DIR}/approval"
+}
+
+# Sends 🟢 once the --ready-on line has been seen or the port accepts
+# connections.
+check_ready() {
+  local elapsed="$1" line=""
+  if [[ -n "$READY_RE" ]]; then
+    [[ -s "${RUN_TMP}/ready" ]] || return 0
+    line="$(<"${RUN_TMP}/ready")"
+  else
+    (( elapsed >= next_ready_poll )) || return 0
+    next_ready_poll=$(( elapsed + READY_POLL_SEC ))
+    host_sh "exec 3<>/dev/tcp/${READY_ADDR}" >/dev/null 2>&1 || return 0
+  fi
+  ready_sent=1
+  event_begin ready "🟢 ${LABEL}${HOST_SUFFIX} ready after $(fmt_duration "$elapsed")$( (( attempt > 1 )) && echo " (attempt ${attempt})")"
+  if [[ -n "$line" ]]; then
+    event_field Line "$(strip_ansi <<<"$line" | cut -c1-300)"
+  else
+    event_field Port "${READY_ADDR/\//:} is accepting connections"
+  fi
+  notify
+}
+
+# Runs the --probe check on the job host; after PROBE_FAILURES failures in
+# a row (once it has passed) sends 🩺, and ✅ when it passes again.
+check_probe() {
+  local check out
+  if [[ "$PROBE_TARGET" =~ ^https?:// ]]; then
+    check="curl -fsS -m 10 -o /dev/null $(printf '%q' "$PROBE_TARGET")"
+  else
+    local host="${PROBE_TARGET%:*}"
+    check="exec 3<>/dev/tcp/${host:-127.0.0.1}/${PROBE_TARGET##*:}"
+  fi
+  probe_checks=$(( probe_checks + 1 ))
+  if out="$(host_sh "$check" 2>&1)"; then
+    if [[ -n "$probe_down" ]]; then
+      event_begin recovered "✅ ${LABEL}${HOST_SUFFIX}: ${PROBE_TARGET} is passing again after $(fmt_duration $(( $(date +%s) - probe_down )))"
+      notify
+    fi
+    probe_ok=1 probe_streak=0 probe_down=""
+    return
+  fi
+  probe_fails=$(( probe_fails + 1 ))
+  probe_streak=$(( probe_streak + 1 ))
+  if [[ -n "$probe_ok" && -z "$probe_down" ]] && (( probe_streak >= PROBE_FAILURES )); then
+    probe_down="$(date +%s)"
+    event_begin probe "🩺 ${LABEL}${HOST_SUFFIX}: ${PROBE_TARGET} failed ${probe_streak} checks in a row (process still running)"
+    event_field Error "$(tail -n 1 <<<"${out:-connection refused}" | sed 's/^bash: line [0-9]*: //' | cut -c1-300)"
+    notify
+  fi
+}
+
+# Takes the number of --level-spike lines in the last minute and alerts
+# when it spikes against the average of the earlier quiet minutes.
+check_level_rate() {
+  local count=0 rate baseline
+  if [[ -f "${RUN_TMP}/levels" ]]; then
+    count="$(wc -l <"${RUN_TMP}/levels")"
+  fi
+  if [[ -z "$level_count" ]]; then
+    level_count="$count"
+    return
+  fi
+  rate=$(( count - level_count ))
+  level_count="$count"
+  baseline="$(awk -v m="$level_minutes" -v t="$level_total" 'BEGIN { printf "%.1f", (m > 0 ? t / m : 0) }')"
+  if [[ -z "$level_spiking" ]] && (( level_minutes >= LEVEL_WARMUP && rate >= LEVEL_SPIKE_MIN )) \
+      && awk -v r="$rate" -v b="$baseline" -v f="$LEVEL_SPIKE_FACTOR" 'BEGIN { exit !(r >= f * b) }'; then
+    level_spiking=1
+    event_begin levels "📣 ${LABEL}${HOST_SUFFIX}: ${rate} warning/error lines in the last minute (usually ${baseline}/min)"
+    event_field "Last match" "$(tail -n 1 "${RUN_TMP}/levels" | cut -c1-300)"
+    notify
+    return
+  fi
+  if [[ -n "$level_spiking" ]]; then
+    if awk -v r="$rate" -v b="$baseline" 'BEGIN { exit !(r <= 2 * b) }'; then
+      level_spiking=""
+    fi
+    return
+  fi
+  level_minutes=$(( level_minutes + 1 ))
+  level_total=$(( level_total + rate ))
+}
+
+# GAP seconds with ±10% jitter, so runs started together do not report
+# together.
+jittered() {
+  echo $(( $1 * (90 + RANDOM % 21) / 100 ))
+}
+
+# Sends a 💓 message while the child has reported no progress, and
+# schedules the next one (gaps grow by HEARTBEAT_FACTOR up to
+# HEARTBEAT_MAX); stops for good once progress shows up.
+check_heartbeat() {
+  local elapsed="$1" progress
+  if [[ -n "$PROGRESS_PERCENT" ]]; then
+    next_heartbeat=""
+    return
+  fi
+  read_activity
+  event_begin heartbeat "💓 ${LABEL}${HOST_SUFFIX} still running after $(fmt_duration "$elapsed")"
+  if progress="$(progress_text)"; then
+    event_field Progress "$progress"
+  fi
+  if [[ -n "$last_line" ]]; then
+    event_field "Last line" "${last_line} ($(fmt_duration $(( $(date +%s) - last_activity ))) ago)"
+  else
+    event_field "Last line" "(none yet)"
+  fi
+  notify
+  heartbeat_gap=$(( heartbeat_gap * HEARTBEAT_FACTOR ))
+  if (( heartbeat_gap > HEARTBEAT_MAX )); then
+    heartbeat_gap="$HEARTBEAT_MAX"
+  fi
+  next_heartbeat=$(( elapsed + $(jittered "$heartbeat_gap") ))
+}
+
+# Adds the "Host context" field (see HOST_CONTEXT) unless turned off.
+add_host_context() {
+  [[ -n "$HOST_CONTEXT_ON" ]] || return 0
+  local context
+  context="$(host_sh "$HOST_CONTEXT" 2>/dev/null)" || return 0
+  if [[ -n "$context" ]]; then
+    event_field "Host context" "$context"
+  fi
+}
+
+check_stall() {
+  local now last_activity last_line
+  now="$(date +%s)"
+  read_activity
+  local silent=$(( now - last_activity ))
+  if [[ -z "$stalled_since" ]] && (( silent >= STALL_AFTER )); then
+    stalled_since="$last_activity"
+    event_begin stall "⚠️ ${LABEL}${HOST_SUFFIX}: no output for $(fmt_duration "$silent")"
+    event_field "Last line" "${last_line:-(none yet)}"
+    add_host_context
+    notify
+  elif [[ -n "$stalled_since" ]] && (( last_activity > stalled_since )); then
+    event_begin resumed "▶️ ${LABEL}${HOST_SUFFIX}: output resumed after $(fmt_duration $(( last_activity - stalled_since ))) of silence"
+    event_field "Line" "$last_line"
+    stalled_since=""
+    notify
+  fi
+}
+
+check_preemption() {
+  local notice
+  notice="$(host_sh "$PREEMPT_PROBE" 2>/dev/null)" || return 0
+  preempt_seen=1
+  event_begin preempt "🚨 ${LABEL}${HOST_SUFFIX}: instance is being preempted — job still running"
+  event_field Notice "$notice"
+  notify
+  if [[ -n "$ON_PREEMPT" ]]; then
+    run_hook on-preempt "$ON_PREEMPT"
+  fi
+}
+
+# Runs hook command $2 (named $1) on the job host under --hook-timeout and
+# adds "on-failure: exit 1 (last line)" to HOOK_RESULTS. A failing or
+# hanging hook never stops the wrapper.
+run_hook() {
+  local out="${RUN_TMP}/hook.out" rc=0 result last
+  host_sh "export OCNOTIFY_LABEL=$(printf '%q' "$LABEL") OCNOTIFY_EXIT=${RC:-}
+timeout -k 5 ${HOOK_TIMEOUT} bash -c $(printf '%q' "$2")" >"$out" 2>&1 </dev/null || rc=$?
+  last="$(strip_ansi <"$out" | awk 'NF { line = $0 } END { print substr(line, 1, 200) }')"
+  if (( rc == 124 || rc == 137 )); then
+    result="${1}: timed out after $(fmt_duration "$HOOK_TIMEOUT")"
+  else
+    result="${1}: exit ${rc}"
+  fi
+  HOOK_RESULTS+="${HOOK_RESULTS:+; }${result}${last:+ (${last})}"
+}
+
+# Holds a sleep inhibitor until this wrapper exits. On failure warns on
+# stderr and keeps the reason in KEEP_AWAKE_FAILED for the final message.
+start_keep_awake() {
+  local err="${RUN_TMP}/inhibit.err" pid
+  if command -v caffeinate >/dev/null 2>&1; then
+    caffeinate -is -w $$ 2>"$err" &
+  elif command -v systemd-inhibit >/dev/null 2>&1; then
+    systemd-inhibit --what=sleep:idle --who=ocnotify --why="${LABEL} is running" --mode=block \
+      sh -c "while kill -0 $$ 2>/dev/null; do sleep 5; done" >/dev/null 2>"$err" &
+  else
+    KEEP_AWAKE_FAILED="neither caffeinate nor systemd-inhibit is installed"
+  fi
+  if [[ -z "$KEEP_AWAKE_FAILED" ]]; then
+    pid=$!
+    sleep 0.5
+    if ! kill -0 "$pid" 2>/dev/null; then
+      KEEP_AWAKE_FAILED="$(head -n 1 "$err")"
+      KEEP_AWAKE_FAILED="${KEEP_AWAKE_FAILED:-inhibitor exited right away}"
+    fi
+  fi
+  if [[ -n "$KEEP_AWAKE_FAILED" ]]; then
+    echo "--keep-awake: could not inhibit sleep: ${KEEP_AWAKE_FAILED}" >&2
+  fi
+}
+
+# Prints "<percent> <ac|battery>" for this machine's battery; fails without
+# one.
+battery_probe() {
+  local dir percent="" source="ac"
+  if command -v pmset >/dev/null 2>&1; then
+    local batt
+    batt="$(pmset -g batt 2>/dev/null)" || return 1
+    [[ "$batt" =~ ([0-9]+)% ]] || return 1
+    percent="${BASH_REMATCH[1]}"
+    if [[ "$batt" == *"'Battery Power'"* ]]; then
+      source="battery"
+    fi
+  else
+    for dir in /sys/class/power_supply/*; do
+      [[ -r "${dir}/type" ]] || continue
+      case "$(<"${dir}/type")" in
+        Battery)
+          if [[ -z "$percent" && -r "${dir}/capacity" ]]; then
+            percent="$(<"${dir}/capacity")"
+            if [[ "$(<"${dir}/status")" == Discharging ]]; then
+              source="battery"
+            fi
+          fi ;;
+      esac
+    done
+    [[ -n "$percent" ]] || return 1
+  fi
+  printf '%s %s' "$percent" "$source"
+}
+
+# Warns once when the machine goes on battery (and again after AC comes back
+# and goes away), and once per drop below the --watch-battery level.
+check_battery() {
+  local reading percent source
+  reading="$(battery_probe)" || return 0
+  read -r percent source <<<"$reading"
+  if [[ "$source" == battery && -z "$on_battery" ]]; then
+    on_battery=1
+    event_begin battery "🔋 ${LABEL}${HOST_SUFFIX}: $(hostname) switched to battery power (${percent}%)"
+    notify
+  elif [[ "$source" == ac && -n "$on_battery" ]]; then
+    on_battery=""
+    event_begin power "🔌 ${LABEL}${HOST_SUFFIX}: $(hostname) is back on AC power (${percent}%)"
+    notify
+  fi
+  if (( percent < WATCH_BATTERY )) && [[ "$source" == battery && -z "$battery_low" ]]; then
+    battery_low=1
+    event_begin battery "🪫 ${LABEL}${HOST_SUFFIX}: battery at ${percent}% on $(hostname), job still running"
+    notify
+  elif (( percent >= WATCH_BATTERY )) || [[ "$source" == ac ]]; then
+    battery_low=""
+  fi
+}
+
+host_shutting_down() {
+  [[ "$(systemctl is-system-running 2>/dev/null)" == "stopping" ]]
+}
+
+# SIGINT/SIGTERM/SIGHUP handler: forward the signal to the child, wait for
+# it to exit (KILL after the --kill-after grace) and report the
+# cancellation through notify_done, like any other end of the run, so
+# killing the wrapper never leaves a silent orphan.
+on_signal() {
+  local sig="$1"
+  trap - INT TERM HUP
+  # A bare "return" in a trap reports the status of the command the signal
+  # interrupted (e.g. 143 from wait), which must not trip errexit.
+  set +e
+  local elapsed=$(( $(date +%s) - start_epoch ))
+  INTERRUPTED="🛑 ${LABEL}${HOST_SUFFIX} cancelled by user after $(fmt_duration "$elapsed")"
+  if [[ -f "${RUN_DIR}/cancel" ]]; then
+    INTERRUPTED="🛑 ${LABEL}${HOST_SUFFIX} cancelled after $(fmt_duration "$elapsed") via oc-notify-ctl.sh"
+  elif [[ "$sig" != INT ]]; then
+    if host_shutting_down; then
+      INTERRUPTED="🔌 ${LABEL}${HOST_SUFFIX} interrupted after $(fmt_duration "$elapsed"): host $(hostname) is shutting down"
+    else
+      INTERRUPTED="🛑 ${LABEL}${HOST_SUFFIX} cancelled after $(fmt_duration "$elapsed"): wrapper received SIG${sig}"
+    fi
+  fi
+  # A child that already exited (e.g. during the --retries backoff) keeps
+  # the status it was reaped with, and its attempt has been wrapped up.
+  if [[ -n "$CHILD_RC" ]]; then
+    if [[ -n "$BACKOFF_PID" ]]; then
+      kill "$BACKOFF_PID" 2>/dev/null
+      # notify_done lists the last attempt itself.
+      unset 'ATTEMPTS[-1]'
+    fi
+  else
+    # Ctrl-C on the terminal already reached a child in our process group.
+    if [[ "$sig" != INT || -z "$CHILD_ON_TTY" ]]; then
+      stop_child "$sig" || true
+    fi
+    local waited=0
+    while kill -0 "$CHILD_PID" 2>/dev/null && (( waited < KILL_AFTER )); do
+      sleep 1
+      waited=$(( waited + 1 ))
+    done
+    if kill -0 "$CHILD_PID" 2>/dev/null; then
+      stop_child KILL || true
+      INTERRUPTED+=" (SIGKILL after ${KILL_AFTER}s grace)"
+    fi
+    reap_child
+    finish_attempt
+  fi
+  RC="$CHILD_RC"
+  notify_done
+  exit $(( 128 + $(kill -l "$sig") ))
+}
+
+# Reads the child's merged output on stdin and writes all of it on, appended
+# to LOG_PATH if set, otherwise to stdout. A copy goes through
+# analysis_pipeline over RUN_TMP/analysis; both are done when this returns.
+output_pipeline() {
+  mkfifo "${RUN_TMP}/analysis"
+  analysis_pipeline <"${RUN_TMP}/analysis" &
+  local analysis=$!
+  if [[ -n "$LOG_PATH" ]]; then
+    mkdir -p "$(dirname "$LOG_PATH")"
+    tee_stage | tee "${RUN_TMP}/analysis" | dedupe_stage | log_stage
+  else
+    tee_stage | tee "${RUN_TMP}/analysis" | dedupe_stage
+  fi
+  wait "$analysis"
+}
+
+# Splits the output into lines, keeps the tail buffer and activity time
+# from every one, then reads progress, metrics and alerts from the
+# --sample-lines sample.
+analysis_pipeline() {
+  cr_stage | watch_stage | buffer_stage | sample_stage | phase_stage | protocol_stage | parser_stage | rule_stage | chunk_stage | alert_stage | level_stage | ready_stage | metric_stage >/dev/null
+}
+
+# Appends value $2 of metric $1, seen at epoch $3, to RUN_TMP/metrics.csv
+# ("time,elapsed_sec,metric,value") and to METRICS_FILE if set.
+record_metric() {
+  local -x TZ=UTC
+  local iso
+  printf -v iso '%(%Y-%m-%dT%H:%M:%SZ)T' "$3"
+  printf '%s,%s,%s,%s\n' "$iso" $(( $3 - start_epoch )) "$1" "$2" >>"${RUN_TMP}/metrics.csv"
+  if [[ "$METRICS_FILE" == *.jsonl ]]; then
+    printf '{"time":"%s","elapsed_sec":%s,"metric":%s,"value":%s}\n' "$iso" $(( $3 - start_epoch )) \
+      "$(json_str "$1")" "$(json_number "$2")" >>"$METRICS_FILE"
+  elif [[ -n "$METRICS_FILE" ]]; then
+    if [[ ! -s "$METRICS_FILE" ]]; then
+      echo "time,elapsed_sec,metric,value" >>"$METRICS_FILE"
+    fi
+    printf '%s,%s,%s,%s\n' "$iso" $(( $3 - start_epoch )) "$1" "$2" >>"$METRICS_FILE"
+  fi
+}
+
+# Passes lines through, writing the first one matching the --ready-on
+# regex to RUN_TMP/ready.
+ready_stage() {
+  if [[ -z "$READY_RE" ]]; then
+    cat
+    return
+  fi
+  "${STREAM_AWK[@]}" -v re="$READY_RE" -v f="${RUN_TMP}/ready" '
+    {
+      print; fflush()
+      if (!seen) {
+        line = $0
+        gsub(/\033\[[0-9;?]*[ -\/]*[@-~]/, "", line)
+        if (line ~ re) { print line > f; close(f); seen = 1 }
+      }
+    }'
+}
+
+# Passes lines through, appending those matching --level-spike to
+# RUN_TMP/levels.
+level_stage() {
+  if [[ -z "$LEVEL_RE" ]]; then
+    cat
+    return
+  fi
+  "${STREAM_AWK[@]}" -v re="$LEVEL_RE" -v f="${RUN_TMP}/levels" '
+    {
+      print; fflush()
+      line = $0
+      gsub(/\033\[[0-9;?]*[ -\/]*[@-~]/, "", line)
+      if (line ~ re) { print line >> f; fflush(f) }
+    }'
+}
+
+# Passes lines through, recording --metric matches like record_metric.
+# bash matches the regexes (awk has no capture groups) and tags each line
+# for one long-lived awk that normalises the values and writes them out.
+metric_stage() {
+  if (( ${#METRIC_NAMES[@]} == 0 )); then
+    cat
+    return
+  fi
+  local -x TZ=UTC
+  local line i now iso
+  while IFS= read -r line || [[ -n "$line" ]]; do
+    printf 'L%s\n' "$line"
+    for i in "${!METRIC_NAMES[@]}"; do
+      if [[ "$line" =~ ${METRIC_REGEXES[$i]} ]]; then
+        printf -v now '%(%s)T' -1
+        printf -v iso '%(%Y-%m-%dT%H:%M:%SZ)T' "$now"
+        printf 'M%s\t%s\t%s\t%s\n' "${METRIC_NAMES[$i]}" "$now" "$iso" "${BASH_REMATCH[1]:-${BASH_REMATCH[0]}}"
+      fi
+    done
+  done | "${STREAM_AWK[@]}" -F '\t' -v f="${RUN_TMP}/metrics.csv" -v out="$METRICS_FILE" -v start="$start_epoch" \
+    -v header="$([[ -n "$METRICS_FILE" && ! -s "$METRICS_FILE" ]] && echo 1)" "${NORMALIZE_AWK}${JSON_AWK}"'
+    /^L/ { print substr($0, 2); fflush(); next }
+    {
+      name = substr($1, 2); value = normalize($4)
+      csv = $3 "," ($2 - start) "," name "," value
+      print csv >> f; fflush(f)
+      if (out ~ /\.jsonl$/) {
+        printf "{\"time\":\"%s\",\"elapsed_sec\":%s,\"metric\":%s,\"value\":%s}\n", $3, $2 - start,
+          json_str(name), json_number(value) >> out
+        fflush(out)
+      } else if (out != "") {
+        if (header) print "time,elapsed_sec,metric,value" >> out
+        header = 0
+        print csv >> out; fflush(out)
+      }
+    }'
+}
+
+# Passes lines through; the first --parse-rule matching a line turns its
+# named groups into a progress report in RUN_TMP/progress. As in
+# metric_stage, bash does the matching and one awk builds the JSON.
+rule_stage() {
+  if (( ${#PARSE_RULE_RES[@]} == 0 )); then
+    cat
+    return
+  fi
+  local line i pair name value record summary
+  while IFS= read -r line || [[ -n "$line" ]]; do
+    printf 'L%s\n' "$line"
+    for i in "${!PARSE_RULE_RES[@]}"; do
+      [[ "$line" =~ ${PARSE_RULE_RES[$i]} ]] || continue
+      record=""
+      summary="${PARSE_RULE_SUMMARIES[$i]}"
+      for pair in ${PARSE_RULE_NAMES[$i]}; do
+        name="${pair#*:}"
+        value="${BASH_REMATCH[${pair%%:*}]//$'\t'/ }"
+        [[ -n "$value" ]] || continue
+        summary="${summary//"{${name}}"/"$value"}"
+        record+=$'\t'"${name}"$'\t'"${value}"
+      done
+      if [[ -n "${PARSE_RULE_SUMMARIES[$i]}" ]]; then
+        record+=$'\t'"summary"$'\t'"${summary//$'\t'/ }"
+      fi
+      printf 'R%s\n' "$record"
+      break
+    done
+  done | "${STREAM_AWK[@]}" -F '\t' -v f="${RUN_TMP}/progress" "${NORMALIZE_AWK}${JSON_AWK}"'
+    /^L/ { print substr($0, 2); fflush(); next }
+    {
+      json = ""
+      for (i = 2; i < NF; i += 2) {
+        if ($i == "summary") {
+          json = json ",\"summary\":" json_str($(i + 1))
+        } else {
+          json = json "," json_str($i) ":" json_number(normalize($(i + 1)))
+        }
+      }
+      print "{" substr(json, 2) "}" >> f; fflush(f)
+    }'
+}
+
+# Writes {"phase": name} to RUN_TMP/progress when a line matches a later
+# --phases pattern. The run starts in the first phase and only moves forward.
+# It runs ahead of protocol_stage and writes before passing the line on, so
+# reports that follow a phase line count towards the new phase.
+phase_stage() {
+  if (( ${#PHASE_RES[@]} < 2 )); then
+    cat
+    return
+  fi
+  "${STREAM_AWK[@]}" -v pats="$(printf '%s\n' "${PHASE_RES[@]}")" -v names="$(printf '%s\n' "${PHASES[@]}")" \
+    -v f="${RUN_TMP}/progress" '
+    BEGIN { n = split(pats, re, "\n"); split(names, name, "\n"); cur = 1 }
+    /^[ \t]*::ocnotify::/ { print; fflush(); next }
+    {
+      line = $0
+      gsub(/\033\[[0-9;?]*[ -\/]*[@-~]/, "", line)
+      for (i = n; i > cur; i--) if (line ~ re[i]) {
+        cur = i
+        s = name[i]; gsub(/[\\"]/, "\\\\&", s)
+        printf "{\"phase\":\"%s\"}\n", s >> f; fflush(f)
+        break
+      }
+      print; fflush()
+    }'
+}
+
+# Copies output lines to RUN_TMP/chunk for --parser-cmd.
+chunk_stage() {
+  if [[ -z "$PARSER_CMD" ]]; then
+    cat
+    return
+  fi
+  "${STREAM_AWK[@]}" -v f="${RUN_TMP}/chunk" '{ print; fflush(); print >> f; fflush(f) }'
+}
+
+# Feeds the output since the last call to --parser-cmd and takes the first
+# JSON object it prints as a progress report. Failures and timeouts are
+# counted for the final message and never stop the run.
+run_parser_cmd() {
+  local chunk="${RUN_TMP}/chunk" size rc=0 report
+  [[ -f "$chunk" ]] || return 0
+  size="$(wc -c <"$chunk")"
+  if (( size <= parser_offset )); then
+    return 0
+  fi
+  tail -c +$(( parser_offset + 1 )) "$chunk" | head -c $(( size - parser_offset )) >"${RUN_TMP}/chunk.in"
+  parser_offset="$size"
+  # Start over once the file is big and fully read; the stage keeps
+  # appending to it.
+  if (( size > 1048576 )); then
+    : >"$chunk"
+    parser_offset=0
+  fi
+  timeout -k 2 "$PARSER_TIMEOUT" bash -c "$PARSER_CMD" <"${RUN_TMP}/chunk.in" \
+    >"${RUN_TMP}/chunk.out" 2>/dev/null || rc=$?
+  report="$(grep -m 1 '^[[:space:]]*{' "${RUN_TMP}/chunk.out" || true)"
+  if [[ -n "$report" ]]; then
+    printf '%s\n' "$report" >>"${RUN_TMP}/progress"
+  elif (( rc != 0 )); then
+    parser_failures=$(( parser_failures + 1 ))
+    parser_error="exit ${rc}"
+    if (( rc == 124 || rc == 137 )); then
+      parser_error="timed out after $(fmt_duration "$PARSER_TIMEOUT")"
+    fi
+  fi
+}
+
+# Takes ::ocnotify::{json} progress lines out of the output and appends their
+# JSON to RUN_TMP/progress for check_progress. Reports over
+# PROGRESS_MAX_BYTES or nested deeper than PROGRESS_MAX_DEPTH never reach a
+# decoder; the first one dropped is reported on stderr.
+protocol_stage() {
+  "${STREAM_AWK[@]}" -v f="${RUN_TMP}/progress" -v max="$PROGRESS_MAX_BYTES" -v depth="$PROGRESS_MAX_DEPTH" '
+    # Whether brackets outside strings nest deeper than the limit.
+    function too_deep(s,  t, n, i, c, d, instr) {
+      t = s
+      if (gsub(/[[{]/, "", t) <= depth) return 0
+      n = length(s)
+      for (i = 1; i <= n; i++) {
+        c = substr(s, i, 1)
+        if (instr) {
+          if (c == "\\") i++
+          else if (c == "\"") instr = 0
+        } else if (c == "\"") {
+          instr = 1
+        } else if (c == "{" || c == "[") {
+          if (++d > depth) return 1
+        } else if (c == "}" || c == "]") {
+          d--
+        }
+      }
+      return 0
+    }
+    /^[ \t]*::ocnotify::/ {
+      sub(/^[ \t]*::ocnotify::/, "")
+      if (length($0) > max || too_deep($0)) {
+        if (!warned++) printf "ocnotify: dropped a progress report over %d bytes or %d levels deep\n", max, depth > "/dev/stderr"
+        next
+      }
+      print >> f; fflush(f); next
+    }
+    { print; fflush() }'
+}
+
+# Built-in --parser profiles, and the tool names that select them for
+# --parser auto (tqdm bars are recognised in any output).
+PARSER_PROFILES="tqdm cargo pytest ffmpeg rsync docker pip wget curl terraform tarpaulin coverage criterion"
+
+# Sets PARSERS from --parser and, for auto, from the tools named in the
+# command ("$@").
+resolve_parsers() {
+  if [[ "$PARSER" != auto ]]; then
+    PARSERS="${PARSER//,/ }"
+    return
+  fi
+  local cmd=" $* " tool
+  PARSERS="tqdm"
+  for tool in ${PARSER_PROFILES#tqdm }; do
+    if [[ "$cmd" =~ [[:space:]/\;\&\|\(\"\']${tool}[0-9]*[[:space:]\;\&\|\)\"\'] ]]; then
+      PARSERS+=" ${tool}"
+    fi
+  done
+}
+
+# Passes lines through and turns progress shown by known tools into
+# progress reports in RUN_TMP/progress, like ::ocnotify:: lines.
+parser_stage() {
+  if [[ -z "$PARSERS" ]]; then
+    cat
+    return
+  fi
+  "${STREAM_AWK[@]}" -v f="${RUN_TMP}/progress" -v parsers=" ${PARSERS} " '
+    function on(p) { return index(parsers, " " p " ") > 0 }
+    function trim(s) { gsub(/^[ \t:|-]+|[ \t:|-]+$/, "", s); return s }
+    function secs(hms,  t) { split(hms, t, ":"); return t[1] * 3600 + t[2] * 60 + t[3] }
+    # Number right after the first match of re in line ("" without one).
+    function grab(re,  s) {
+      if (!match(line, re)) return ""
+      s = substr(line, RSTART, RLENGTH)
+      sub(/^[^0-9]*/, "", s)
+      return s
+    }
+    function emit(pct, cur, tot, summary,  out) {
+      out = ""
+      if (pct != "") out = out ",\"percent\":" pct
+      if (cur != "" && tot != "") out = out ",\"current\":" cur ",\"total\":" tot
+      if (summary != "") { gsub(/[\\"]/, "", summary); out = out ",\"summary\":\"" substr(summary, 1, 80) "\"" }
+      if (out == "" || out == last) return
+      last = out
+      print "{" substr(out, 2) "}" >> f; fflush(f)
+    }
+    # Records metric name with a numeric value, like an extra report key.
+    function metric(name, value) { print "{\"" name "\":" value "}" >> f; fflush(f) }
+    # Seconds in value v of a criterion time unit (ps to s).
+    function bench_secs(v, unit) {
+      if (unit == "ps") return v / 1e12
+      if (unit == "ns") return v / 1e9
+      if (unit == "us" || unit == "µs") return v / 1e6
+      if (unit == "ms") return v / 1e3
+      return v
+    }
+    {
+      print; fflush()
+      line = $0
+      gsub(/\033\[[0-9;?]*[ -\/]*[@-~]/, "", line)
+      if (on("tqdm") && match(line, /[0-9]+%\|/)) {
+        pct = substr(line, RSTART, RLENGTH - 2)
+        desc = trim(substr(line, 1, RSTART - 1))
+        n = grab("\\| *[0-9]+/[0-9]+")
+        split(n, ct, "/")
+        emit(pct, ct[1], ct[2], desc)
+      }
+      if (on("pytest") && match(line, /\[ *[0-9]+%\] *$/)) {
+        split(line, w, " ")
+        emit(grab("\\[ *[0-9]+"), "", "", w[1])
+      }
+      if (on("ffmpeg")) {
+        if (!duration && (d = grab("Duration: [0-9]+:[0-9]+:[0-9.]+")) != "") duration = secs(d)
+        if (duration > 0 && (t = grab("time=[0-9]+:[0-9]+:[0-9.]+")) != "")
+          emit(sprintf("%.1f", secs(t) / duration * 100), "", "", "encoded " t)
+      }
+      if (on("rsync") && (n = grab("(to|ir)-chk=[0-9]+/[0-9]+")) != "") {
+        split(n, ct, "/")
+        emit(sprintf("%.1f", (ct[2] - ct[1]) / ct[2] * 100), ct[2] - ct[1], ct[2], rsync_file)
+      } else if (on("rsync") && line ~ /^[^ \t]/ && line !~ /^(sending|receiving|sent|total) /) {
+        rsync_file = line
+      }
+      if (on("docker") && (n = grab("(Step |\\[([^]]* )?)[0-9]+/[0-9]+")) != "") {
+        split(n, ct, "/")
+        rest = line
+        sub(/^.*(Step [0-9]+\/[0-9]+ : |\[[^]]*[0-9]+\/[0-9]+\] )/, "", rest)
+        emit(sprintf("%.1f", ct[1] / ct[2] * 100), ct[1], ct[2], rest)
+      }
+      if (on("cargo")) {
+        if ((n = grab("Building \\[[^]]*\\] [0-9]+/[0-9]+")) != "") {
+          split(n, ct, "/")
+          emit(sprintf("%.1f", ct[1] / ct[2] * 100), ct[1], ct[2], "building")
+        } else if (match(line, /^ *Compiling [^ ]+/)) {
+          emit("", "", "", trim(substr(line, RSTART, RLENGTH)))
+        } else if ((n = grab("^running [0-9]+ tests?")) != "") {
+          tests += n
+        } else if (tests > 0 && line ~ /^test .* \.\.\. /) {
+          done_tests++
+          emit(sprintf("%.1f", done_tests / tests * 100), done_tests, tests, "testing")
+        }
+      }
+      if (on("pip") && match(line, /^(Collecting|Downloading|Installing collected packages:|Successfully installed) ?[^ ]*/))
+        emit("", "", "", trim(substr(line, RSTART, RLENGTH)))
+      if (on("wget") && (n = grab("^ *[0-9]+[KMG] [. ]+ *[0-9]+%")) != "") {
+        sub(/.* /, "", n)
+        emit(n + 0, "", "", "downloading")
+      }
+      if (on("curl")) {
+        if (line ~ /% Total +% Received/) curl_meter = 1
+        else if (curl_meter && line ~ /^ *[0-9]+ /) { split(line, w, " "); emit(w[1] + 0, "", "", "downloading") }
+      }
+      if (on("terraform")) {
+        if ((n = grab("Plan: [0-9]+ to add")) != "") {
+          tf_total = n + grab("[0-9]+ to change") + grab("[0-9]+ to destroy")
+        } else if (tf_total > 0 && match(line, /^[^ ]+: (Creation|Modifications|Destruction) complete/)) {
+          tf_done++
+          split(line, w, ":")
+          emit(sprintf("%.1f", tf_done / tf_total * 100), tf_done, tf_total, w[1])
+        }
+      }
+      if (on("tarpaulin") && (n = grab("^[0-9.]+% coverage,")) != "")
+        metric("coverage", n + 0)
+      if (on("coverage") && line ~ /^TOTAL( +[0-9]+)+ +[0-9.]+% *$/) {
+        n = split(line, w, " ")
+        metric("coverage", w[n] + 0)
+      }
+      if (on("criterion")) {
+        if (match(line, /time: +\[[^]]*\]/)) {
+          name = trim(substr(line, 1, RSTART - 1))
+          if (name == "") name = bench_prev
+          split(substr(line, RSTART, RLENGTH), w, /[][ ]+/)
+          gsub(/\//, ".", name)
+          gsub(/[^A-Za-z0-9_.-]+/, "_", name)
+          if (name != "" && w[4] != "") metric("bench." name, sprintf("%.6g", bench_secs(w[4], w[5])))
+        } else if (line ~ /^[^ \t]/) {
+          bench_prev = line
+        }
+      }
+    }'
+}
+
+# Prints "key<TAB>value" for the string, number, boolean and null members of
+# a JSON object, with nested objects flattened and arrays skipped; newlines
+# and tabs in values become spaces, and keys and values are cut to
+# JSON_FIELD_MAX characters. Reports with escapes or arrays are decoded by
+# JSON_DECODER (jq or python3, when installed), which turn invalid UTF-8
+# into U+FFFD; plain ones, all of them without a decoder, and truncated or
+# malformed ones a decoder rejects go to json_fields_regex, which keeps the
+# members ahead of the damage.
+json_fields() {
+  if [[ -n "$JSON_DECODER" && "$1" == *[\[\\]* ]]; then
+    case "$JSON_DECODER" in
+      jq) jq -r --argjson max "$JSON_FIELD_MAX" "$JSON_FIELDS_JQ" <<<"$1" 2>/dev/null && return ;;
+      python3) python3 -c "$JSON_FIELDS_PY" "$1" "$JSON_FIELD_MAX" 2>/dev/null && return ;;
+    esac
+  fi
+  json_fields_regex "$1"
+}
+
+JSON_DECODER=""
+if command -v jq >/dev/null 2>&1; then
+  JSON_DECODER=jq
+elif command -v python3 >/dev/null 2>&1; then
+  JSON_DECODER=python3
+fi
+
+JSON_FIELDS_JQ='
+  def clean: tostring | gsub("[\t\n\r]"; " ") | .[:$max];
+  def fields: to_entries[]
+    | if (.value | type) == "object" then .value | fields
+      elif (.value | type) == "array" then empty
+      else "\(.key | clean)\t\(.value | clean)" end;
+  if type == "object" then fields else empty end'
+
+JSON_FIELDS_PY='
+import json, os, sys
+def clean(v):
+    if v is None or isinstance(v, bool):
+        v = json.dumps(v)
+    return " ".join(str(v).replace("\t", " ").replace("\r", " ").split("\n"))[:int(sys.argv[2])]
+def fields(o):
+    for k, v in o.items():
+        if isinstance(v, dict):
+            fields(v)
+        elif not isinstance(v, list):
+            out.append("%s\t%s\n" % (clean(k), clean(v)))
+out = []
+o = json.loads(os.fsencode(sys.argv[1]).decode("utf-8", "replace"))
+if isinstance(o, dict):
+    fields(o)
+sys.stdout.buffer.write("".join(out).encode("utf-8"))
+'
+
+# Regex fallback for json_fields, enough for flat progress reports. Known
+# limits: an array ends the object (it and every later member are dropped),
+# only \" \\ and \n are unescaped, so \uXXXX, \t and \/ stay as written, and
+# invalid UTF-8 is passed through.
+json_fields_regex() {
+  local json="$1" value
+  local re='^[[:space:],{}]*"(([^"\\]|\\.)*)"[[:space:]]*:[[:space:]]*("(([^"\\]|\\.)*)"|-?[0-9.]+([eE][+-]?[0-9]+)?|true|false|null|\{)'
+  while [[ "$json" =~ $re ]]; do
+    json="${json:${#BASH_REMATCH[0]}}"
+    value="${BASH_REMATCH[3]}"
+    if [[ "$value" == "{" ]]; then
+      continue
+    elif [[ "$value" == \"* ]]; then
+      value="${BASH_REMATCH[4]}"
+      value="${value//\\n/ }"
+      value="${value//\\\"/\"}"
+      value="${value//\\\\/\\}"
+    fi
+    printf '%s\t%s\n' "${BASH_REMATCH[1]:0:JSON_FIELD_MAX}" "${value:0:JSON_FIELD_MAX}"
+  done
+}
+
+# Applies the progress reports written by protocol_stage since the last tick.
+check_progress() {
+  local json key value now percent current total summary phase phase_at job job_exit job_at approve
+  while IFS= read -r json; do
+    progress_seen=$(( progress_seen + 1 ))
+    percent="" current="" total="" summary="" phase="" phase_at="" job="" job_exit="" job_at="" approve=""
+    printf -v now '%(%s)T' -1
+    while IFS=$'\t' read -r key value; do
+      case "$key" in
+        percent) percent="$value" ;;
+        current) current="$value" ;;
+        total) total="$value" ;;
+        summary) summary="$value" ;;
+        phase) phase="$value" ;;
+        # Sent by CHAIN_RUNNER: when a --step command started and ended.
+        phase_at) phase_at="$value" ;;
+        step_exit) STEP_EXITS[$(( phase_index - 1 ))]="$value" ;;
+        # Added by JOB_RUNNER to a --job command's reports.
+        job) job="$value" ;;
+        job_exit) job_exit="$value" ;;
+        job_at) job_at="$value" ;;
+        artifact) ARTIFACTS+=("$value") ;;
+        approve) approve="$value" ;;
+        *)
+          if [[ "$value" =~ ^-?[0-9.]+([eE][+-]?[0-9]+)?$ ]]; then
+            record_metric "${job:+${job}.}${key}" "$value" "$now"
+          fi ;;
+      esac
+    done < <(json_fields "$json")
+    if [[ -n "$job" && -n "${JOB_INDEX[$job]:-}" ]]; then
+      job_progress "$job" "$percent" "$current" "$total" "$job_exit" "${job_at:-$now}"
+      continue
+    fi
+    if [[ -n "$approve" ]]; then
+      request_approval "$approve"
+    fi
+    if [[ -n "$phase" ]]; then
+      set_phase "$phase" "${phase_at:-$now}"
+    fi
+    update_progress "$percent" "$summary" "$current" "$total" output
+  done < <(tail -n +$(( progress_seen + 1 )) "${RUN_TMP}/progress")
+}
+
+# Records a progress report from --job $1 (percent, current, total, plus
+# the exit status and end time once it is done), alerts when it failed and
+# turns all jobs into one report: the average percent, with finished jobs
+# at 100, and "3/8 jobs done, slowest at 40%".
+job_progress() {
+  local job="$1" percent="$2" current="$3" total="$4" rc="$5" at="$6" name done=0 failed=0 running=0 sum=0 slowest=""
+  if [[ -z "$percent" && -n "$current" && -n "$total" ]]; then
+    percent="$(awk -v c="$current" -v t="$total" 'BEGIN { if (t > 0) printf "%.4g", c / t * 100 }')"
+  fi
+  if [[ -n "$percent" ]] && is_number "$percent"; then
+    JOB_PERCENT[$job]="$percent"
+  fi
+  if [[ -n "$rc" ]]; then
+    JOB_EXIT[$job]="$rc"
+    JOB_END[$job]="$at"
+  fi
+  for name in "${JOB_LABELS[@]}"; do
+    case "${JOB_EXIT[$name]:-}" in
+      "") ;;
+      0) done=$(( done + 1 )) ;;
+      *) failed=$(( failed + 1 )) ;;
+    esac
+  done
+  if [[ -n "$rc" && "$rc" != 0 ]]; then
+    event_begin job "❌ ${LABEL}${HOST_SUFFIX}: job ${job} failed (exit=${rc}), ${done}/${#JOB_LABELS[@]} done"
+    event_field Elapsed "$(fmt_duration $(( at - start_epoch )))"
+    event_tail "$(TAIL_LINES=1000 output_tail | grep -F "[${job}] " | tail -n 10)"
+    notify
+  fi
+  for name in "${JOB_LABELS[@]}"; do
+    if [[ -n "${JOB_EXIT[$name]:-}" ]]; then
+      sum="$(awk -v s="$sum" 'BEGIN { print s + 100 }')"
+      continue
+    fi
+    running=$(( running + 1 ))
+    sum="$(awk -v s="$sum" -v p="${JOB_PERCENT[$name]:-0}" 'BEGIN { print s + p }')"
+    if [[ -z "$slowest" ]] || awk -v p="${JOB_PERCENT[$name]:-0}" -v m="$slowest" 'BEGIN { exit !(p < m) }'; then
+      slowest="${JOB_PERCENT[$name]:-0}"
+    fi
+  done
+  local summary="${done}/${#JOB_LABELS[@]} jobs done"
+  if (( failed > 0 )); then
+    summary+=", ${failed} failed"
+  fi
+  if (( running > 0 )); then
+    summary+=", slowest at ${slowest%.*}%"
+  fi
+  update_progress "$(awk -v s="$sum" -v n="${#JOB_LABELS[@]}" 'BEGIN { printf "%.1f", s / n }')" "$summary" \
+    "$done" "${#JOB_LABELS[@]}" output
+}
+
+# "✓ shard1 3m02s · ✗ shard2 (exit 1) 1m10s · ✗ shard3 (interrupted)" for
+# the --job commands.
+jobs_summary() {
+  local name out="" item
+  for name in "${JOB_LABELS[@]}"; do
+    case "${JOB_EXIT[$name]:-}" in
+      0) item="✓ ${name} $(fmt_duration $(( JOB_END[$name] - start_epoch )))" ;;
+      "") item="✗ ${name} (interrupted)" ;;
+      *) item="✗ ${name} (exit ${JOB_EXIT[$name]}) $(fmt_duration $(( JOB_END[$name] - start_epoch )))" ;;
+    esac
+    out+="${out:+ · }${item}"
+  done
+  printf '%s' "$out"
+}
+
+# Records a progress report (percent, summary, current, total; any may be
+# empty) from a source (output, file or count) and sends a 📈 message for the
+# highest --milestones value it passes.
+update_progress() {
+  local percent="$1" summary="${2:-}" current="${3:-}" total="${4:-}" source="${5:-output}"
+  if (( ${#PROGRESS_WEIGHTS[@]} > 0 )); then
+    combine_progress "$source" "$percent" "$summary" "$current" "$total" || return 0
+    percent="$PROGRESS_PERCENT"
+  else
+    if [[ -n "$current" ]] && is_number "$current"; then
+      PROGRESS_CURRENT="$current"
+    fi
+    if [[ -n "$total" ]] && is_number "$total"; then
+      PROGRESS_TOTAL="$total"
+    fi
+    if [[ -z "$percent" && -n "$current" && -n "$PROGRESS_TOTAL" ]]; then
+      percent="$(awk -v c="$PROGRESS_CURRENT" -v t="$PROGRESS_TOTAL" 'BEGIN { if (t > 0) printf "%.4g", c / t * 100 }')"
+    fi
+    if [[ -n "$summary" ]]; then
+      PROGRESS_SUMMARY="$summary"
+    fi
+  fi
+  if [[ -z "$percent" ]] || ! is_number "$percent"; then
+    return 0
+  fi
+  local now
+  printf -v now '%(%s)T' -1
+  if [[ "$PROGRESS_SMOOTHING" != raw ]]; then
+    if [[ -n "$PROGRESS_PEAK" ]] && awk -v p="$percent" -v m="$PROGRESS_PEAK" 'BEGIN { exit !(p < m) }'; then
+      percent="$PROGRESS_PEAK"
+    fi
+    PROGRESS_PEAK="$percent"
+  fi
+  if [[ "$PROGRESS_SMOOTHING" == ewma ]]; then
+    update_rate "$now" "$percent"
+  fi
+  PROGRESS_PERCENT="$percent"
+  printf '%s %s %s\n' "$now" "$percent" "${PROGRESS_CURRENT:-}" >>"${RUN_TMP}/progress.samples"
+  local m crossed=0
+  for m in ${MILESTONES//,/ }; do
+    if (( m > milestone_sent && ${percent%.*} >= m )); then
+      crossed="$m"
+    fi
+  done
+  if (( crossed > 0 )); then
+    milestone_sent="$crossed"
+    send_milestone
+  fi
+}
+
+# Folds the percent $2 reached at epoch $1 into PROGRESS_RATE, percent per
+# second averaged with weights that halve every PROGRESS_HALF_LIFE seconds.
+# Reports in the same second as the last one wait for the next.
+update_rate() {
+  local now="$1" percent="$2"
+  if [[ -n "$PROGRESS_RATE_AT" ]]; then
+    (( now > PROGRESS_RATE_AT )) || return 0
+    PROGRESS_RATE="$(awk -v r="$PROGRESS_RATE" -v dt=$(( now - PROGRESS_RATE_AT )) -v dp="$percent" \
+      -v from="$PROGRESS_RATE_FROM" -v h="$PROGRESS_HALF_LIFE" 'BEGIN {
+        x = (dp - from) / dt
+        printf "%.6g", (r == "" ? x : r + (1 - 2 ^ (-dt / h)) * (x - r))
+      }')"
+  fi
+  PROGRESS_RATE_AT="$now"
+  PROGRESS_RATE_FROM="$percent"
+}
+
+# Keeps the latest report per --progress-sources source and sets
+# PROGRESS_PERCENT, SUMMARY, CURRENT and TOTAL from them by --progress-policy.
+# Fails when the source is not listed or nothing has a percent yet.
+combine_progress() {
+  local source="$1" percent="$2" summary="$3" current="$4" total="$5" s p pick="" best=""
+  [[ -n "${PROGRESS_WEIGHTS[$source]:-}" ]] || return 1
+  if [[ -n "$current" ]] && is_number "$current"; then
+    SOURCE_CURRENT[$source]="$current"
+  fi
+  if [[ -n "$total" ]] && is_number "$total"; then
+    SOURCE_TOTAL[$source]="$total"
+  fi
+  if [[ -z "$percent" && -n "$current" && -n "${SOURCE_TOTAL[$source]:-}" ]]; then
+    percent="$(awk -v c="$current" -v t="${SOURCE_TOTAL[$source]}" 'BEGIN { if (t > 0) printf "%.4g", c / t * 100 }')"
+  fi
+  if [[ -n "$summary" ]]; then
+    SOURCE_SUMMARY[$source]="$summary"
+  fi
+  if [[ -n "$percent" ]] && is_number "$percent"; then
+    SOURCE_PERCENT[$source]="$percent"
+  fi
+  (( ${#SOURCE_PERCENT[@]} > 0 )) || return 1
+  case "$PROGRESS_POLICY" in
+    first)
+      for s in "${PROGRESS_SOURCE_ORDER[@]}"; do
+        if [[ -n "${SOURCE_PERCENT[$s]:-}" ]]; then
+          pick="$s"
+          break
+        fi
+      done ;;
+    max)
+      for s in "${PROGRESS_SOURCE_ORDER[@]}"; do
+        p="${SOURCE_PERCENT[$s]:-}"
+        if [[ -n "$p" ]] && { [[ -z "$best" ]] || awk -v a="$p" -v b="$best" 'BEGIN { exit !(a > b) }'; }; then
+          pick="$s"
+          best="$p"
+        fi
+      done ;;
+  esac
+  if [[ -n "$pick" ]]; then
+    PROGRESS_PERCENT="${SOURCE_PERCENT[$pick]}"
+    PROGRESS_SUMMARY="${SOURCE_SUMMARY[$pick]:-}"
+    PROGRESS_CURRENT="${SOURCE_CURRENT[$pick]:-}"
+    PROGRESS_TOTAL="${SOURCE_TOTAL[$pick]:-}"
+    return 0
+  fi
+  local pairs="" parts=""
+  for s in "${PROGRESS_SOURCE_ORDER[@]}"; do
+    p="${SOURCE_PERCENT[$s]:-0}"
+    pairs+="${p} ${PROGRESS_WEIGHTS[$s]} "
+    parts="${parts:+${parts}, }${s} ${p%.*}%"
+  done
+  PROGRESS_PERCENT="$(awk -v l="$pairs" 'BEGIN { n = split(l, a, " "); for (i = 1; i < n; i += 2) { s += a[i] * a[i + 1]; w += a[i + 1] } printf "%.1f", (w > 0 ? s / w : 0) }')"
+  PROGRESS_SUMMARY="$parts"
+  PROGRESS_CURRENT=""
+  PROGRESS_TOTAL=""
+}
+
+# Progress from the size of --progress-from-file on the job host.
+check_file_progress() {
+  local size
+  size="$(host_sh "stat -c %s $(printf '%q' "$PROGRESS_FILE") 2>/dev/null || stat -f %z $(printf '%q' "$PROGRESS_FILE")" 2>/dev/null)" \
+    || return 0
+  [[ "$size" =~ ^[0-9]+$ ]] || return 0
+  update_progress "$(awk -v s="$size" -v t="$PROGRESS_FILE_SIZE" 'BEGIN { p = s / t * 100; printf "%.1f", (p > 100 ? 100 : p) }')" \
+    "$(basename "$PROGRESS_FILE") at $(fmt_bytes "$size") of $(fmt_bytes "$PROGRESS_FILE_SIZE")" "" "" file
+}
+
+# Progress from the number of files matching --progress-from-count on the
+# job host. The glob is left unquoted so the host shell expands it.
+check_count_progress() {
+  local count
+  count="$(host_sh "shopt -s nullglob globstar; set -- ${PROGRESS_GLOB}; echo \$#" 2>/dev/null)" || return 0
+  [[ "$count" =~ ^[0-9]+$ ]] || return 0
+  update_progress "$(awk -v c="$count" -v t="$PROGRESS_GLOB_COUNT" 'BEGIN { p = c / t * 100; printf "%.1f", (p > 100 ? 100 : p) }')" \
+    "${count} of ${PROGRESS_GLOB_COUNT} files" "$count" "$PROGRESS_GLOB_COUNT" count
+}
+
+# Moves to phase $1 (added to PHASES when not declared) at epoch $2. Progress
+# and milestones start over in each phase.
+set_phase() {
+  local name="$1" when="$2" i
+  for (( i = 0; i < ${#PHASES[@]}; i++ )); do
+    [[ "${PHASES[$i]}" == "$name" ]] && break
+  done
+  if (( i == ${#PHASES[@]} )); then
+    PHASES+=("$name")
+  fi
+  if (( i + 1 == phase_index )); then
+    return 0
+  fi
+  phase_index=$(( i + 1 ))
+  PHASE_SEEN+=("$name")
+  PHASE_SEEN_AT+=("$when")
+  PROGRESS_PERCENT="" PROGRESS_CURRENT="" PROGRESS_TOTAL="" PROGRESS_SUMMARY=""
+  PROGRESS_PEAK="" PROGRESS_RATE="" PROGRESS_RATE_AT="" PROGRESS_RATE_FROM=""
+  SOURCE_PERCENT=() SOURCE_SUMMARY=() SOURCE_CURRENT=() SOURCE_TOTAL=()
+  milestone_sent=0
+}
+
+# "build 1m 2s, test 30s" from the phases seen up to epoch $1.
+phase_summary() {
+  local i out="" until
+  for (( i = 0; i < ${#PHASE_SEEN[@]}; i++ )); do
+    until="${PHASE_SEEN_AT[$(( i + 1 ))]:-$1}"
+    out+="${out:+, }${PHASE_SEEN[$i]} $(fmt_duration $(( until - PHASE_SEEN_AT[i] )))"
+  done
+  printf '%s' "$out"
+}
+
+# "~40% by time": elapsed time against --expected (or the label's typical
+# duration), capped at 99%; fails when neither is known.
+time_estimate() {
+  local expected="${EXPECTED_SEC:-${TYPICAL_SEC:-}}"
+  [[ -n "$expected" && -n "${start_epoch:-}" ]] && (( expected > 0 )) || return 1
+  local elapsed=$(( $(date +%s) - start_epoch )) percent
+  percent=$(( elapsed * 100 / expected ))
+  printf '~%d%% by time' $(( percent > 99 ? 99 : percent ))
+}
+
+# "SECONDS CHANGE LATEST" of progress sample column COL (2 percent, 3
+# current) over the last WINDOW seconds, or since the start with 0. Only
+# samples after the last drop count, so a counter that resets starts over;
+# fails without two samples that moved forward.
+progress_delta() {
+  [[ -s "${RUN_TMP}/progress.samples" ]] || return 1
+  awk -v col="$1" -v w="$2" '
+    $col != "" { n++; t[n] = $1; v[n] = $col }
+    END {
+      s = 1
+      for (i = 2; i <= n; i++) if (v[i] < v[i - 1]) s = i
+      for (i = s; i < n && w > 0 && t[i] < t[n] - w; i++) ;
+      if (n < 2 || t[n] <= t[i] || v[n] <= v[i]) exit 1
+      print t[n] - t[i], v[n] - v[i], v[n]
+    }' "${RUN_TMP}/progress.samples"
+}
+
+# Time left at the pace of the last PROGRESS_WINDOW seconds of progress, or
+# at PROGRESS_RATE with --progress-smoothing ewma, e.g. "12m"; fails until
+# percent has moved.
+progress_eta() {
+  local dt dp p
+  if [[ "$PROGRESS_SMOOTHING" == ewma ]]; then
+    awk -v r="$PROGRESS_RATE" 'BEGIN { exit !(r > 0) }' || return 1
+    fmt_duration "$(awk -v r="$PROGRESS_RATE" -v p="$PROGRESS_PERCENT" 'BEGIN { printf "%d", (100 - p) / r }')"
+    return
+  fi
+  read -r dt dp p < <(progress_delta 2 "$PROGRESS_WINDOW") || return 1
+  fmt_duration "$(awk -v dt="$dt" -v dp="$dp" -v p="$p" 'BEGIN { printf "%d", (100 - p) * dt / dp }')"
+}
+
+# Items per unit of time from the progress counter over the last WINDOW
+# seconds (0 for the whole run), e.g. "3.2/s", "120/min" or "4.5/h".
+progress_rate() {
+  local dt dc c
+  read -r dt dc c < <(progress_delta 3 "$1") || return 1
+  awk -v dc="$dc" -v dt="$dt" 'BEGIN {
+    r = dc / dt
+    if (r >= 1) printf "%.3g/s", r
+    else if (r * 60 >= 1) printf "%.3g/min", r * 60
+    else printf "%.3g/h", r * 3600
+  }'
+}
+
+# "Phase 2/4: test — 42% (3/8): epoch 3/8" from the current phase and the
+# latest progress report.
+progress_text() {
+  local phase=""
+  if (( ${phase_index:-0} > 0 )); then
+    phase="${PHASE_WORD} ${phase_index}/${#PHASES[@]}: ${PHASES[$(( phase_index - 1 ))]}"
+  fi
+  if [[ -z "$PROGRESS_PERCENT" ]]; then
+    local estimate=""
+    estimate="$(time_estimate)" || true
+    [[ -n "$phase" || -n "$estimate" ]] || return 1
+    printf '%s' "${phase}${phase:+${estimate:+ — }}${estimate}"
+    return 0
+  fi
+  if [[ -n "$phase" ]]; then
+    printf '%s — ' "$phase"
+  fi
+  printf '%s%%' "$PROGRESS_PERCENT"
+  if [[ -n "$PROGRESS_CURRENT" && -n "$PROGRESS_TOTAL" ]]; then
+    printf ' (%s/%s)' "$PROGRESS_CURRENT" "$PROGRESS_TOTAL"
+  fi
+  if [[ -n "$PROGRESS_SUMMARY" ]]; then
+    printf ': %s' "$PROGRESS_SUMMARY"
+  fi
+}
+
+send_milestone() {
+  local elapsed=$(( $(date +%s) - start_epoch )) metrics trend name eta rate
+  event_begin progress "📈 ${LABEL}${HOST_SUFFIX}: $(progress_text)"
+  event_field Elapsed "$(fmt_duration "$elapsed")"
+  if (( ${PROGRESS_PERCENT%.*} < 100 )) && eta="$(progress_eta)"; then
+    event_field Remaining "~${eta}"
+  fi
+  if rate="$(progress_rate "$PROGRESS_WINDOW")"; then
+    event_field Rate "$rate"
+  fi
+  if [[ -n "$COST_RATE" ]]; then
+    event_field Cost "~\$$(cost_so_far "$elapsed")"
+  fi
+  if metrics="$(metric_summary)"; then
+    event_field Metrics "$metrics"
+  fi
+  if name="$(primary_metric)" && trend="$(metric_sparkline "$name")"; then
+    event_field Trend "${name} ${trend}"
+  fi
+  notify
+  metrics_reported
+}
+
+# Latest recorded value of --metric NAME; fails if there is none yet.
+metric_value() {
+  awk -F, -v m="$1" '$3 == m { v = $4; found = 1 } END { if (!found) exit 1; print v }' \
+    "${RUN_TMP}/metrics.csv" 2>/dev/null
+}
+
+# Value of metric NAME the last reported change is measured from: the value
+# in the last message that showed it, else the first recorded one.
+metric_baseline() {
+  local files=("${RUN_TMP}/metrics.csv")
+  if [[ -f "${RUN_TMP}/metrics.reported" ]]; then
+    files=("${RUN_TMP}/metrics.reported" "${files[@]}")
+  fi
+  awk -F, -v m="$1" '$3 == m { print $4; exit }' "${files[@]}"
+}
+
+# Change of metric NAME since its baseline, e.g. "↓0.02 ✓" ("✓"/"✗" only
+# with a --metric-goal); prints nothing for non-numeric values.
+metric_trend() {
+  local value base
+  value="$(metric_value "$1")" || return 0
+  base="$(metric_baseline "$1")"
+  awk -v v="$value" -v b="$base" -v goal="${METRIC_GOALS[$1]:-}" 'BEGIN {
+    num = "^-?[0-9.]+([eE][+-]?[0-9]+)?$"
+    if (v !~ num || b !~ num) exit
+    d = v - b
+    if (d == 0) { printf "→"; exit }
+    printf "%s%g", (d > 0 ? "↑" : "↓"), (d > 0 ? d : -d)
+    if (goal != "") printf " %s", ((d < 0) == (goal == "min") ? "✓" : "✗")
+  }'
+}
+
+# Latest value of each --metric with its trend, as
+# "loss=0.31 (↓0.02 ✓), acc=0.87 (↑0.05)"; fails if none was recorded.
+metric_summary() {
+  [[ -s "${RUN_TMP}/metrics.csv" ]] || return 1
+  local name out="" trend
+  while read -r name; do
+    out+="${out:+, }${name}=$(metric_value "$name")"
+    trend="$(metric_trend "$name")"
+    if [[ -n "$trend" && "$trend" != "→" ]]; then
+      out+=" (${trend})"
+    fi
+  done < <(awk -F, '!seen[$3]++ { print $3 }' "${RUN_TMP}/metrics.csv")
+  printf '%s' "$out"
+}
+
+# The metric shown as a sparkline: the first --metric, else the first one a
+# progress report recorded.
+primary_metric() {
+  if (( ${#METRIC_NAMES[@]} > 0 )); then
+    printf '%s' "${METRIC_NAMES[0]}"
+  elif [[ -s "${RUN_TMP}/metrics.csv" ]]; then
+    awk -F, 'NR == 1 { print $3 }' "${RUN_TMP}/metrics.csv"
+  else
+    return 1
+  fi
+}
+
+# Sparkline of metric NAME over the run (up to 24 buckets, each the mean of
+# its values) with the first and last value, e.g. "▇▆▅▃▂▁ 0.9 → 0.31";
+# fails with fewer than two numeric values.
+metric_sparkline() {
+  awk -F, -v m="$1" '
+    $3 == m && $4 ~ /^-?[0-9.]+([eE][+-]?[0-9]+)?$/ { v[++n] = $4 + 0; raw[n] = $4 }
+    END {
+      if (n < 2) exit 1
+      split("▁ ▂ ▃ ▄ ▅ ▆ ▇ █", bar, " ")
+      k = n < 24 ? n : 24
+      for (b = 1; b <= k; b++) {
+        lo_i = int((b - 1) * n / k) + 1; hi_i = int(b * n / k); sum = 0
+        for (i = lo_i; i <= hi_i; i++) sum += v[i]
+        avg[b] = sum / (hi_i - lo_i + 1)
+        if (b == 1 || avg[b] < lo) lo = avg[b]
+        if (b == 1 || avg[b] > hi) hi = avg[b]
+      }
+      for (b = 1; b <= k; b++) printf "%s", bar[(hi > lo) ? int((avg[b] - lo) / (hi - lo) * 7.999) + 1 : 4]
+      printf " %s → %s", raw[1], raw[n]
+    }' "${RUN_TMP}/metrics.csv" 2>/dev/null
+}
+
+# Records the current metric values as reported, so the next message shows
+# changes from here.
+metrics_reported() {
+  if [[ -s "${RUN_TMP}/metrics.csv" ]]; then
+    awk -F, '{ last[$3] = $0 } END { for (m in last) print last[m] }' "${RUN_TMP}/metrics.csv" \
+      >"${RUN_TMP}/metrics.reported"
+  fi
+}
+
+# Renders RUN_TMP/metrics.csv as one small chart per metric against elapsed
+# time and prints the image path; fails if there is nothing to plot.
+plot_metrics() {
+  local csv="${RUN_TMP}/metrics.csv" name="${LABEL//[^A-Za-z0-9._-]/_}"
+  if [[ ! -s "$csv" ]]; then
+    return 1
+  fi
+  local metrics=() m
+  mapfile -t metrics < <(awk -F, '$4 ~ /^-?[0-9.]+([eE][+-]?[0-9]+)?$/ && !seen[$3]++ { print $3 }' "$csv")
+  if (( ${#metrics[@]} == 0 )); then
+    return 1
+  fi
+  if command -v gnuplot >/dev/null 2>&1; then
+    local out="${RUN_TMP}/${name}-metrics.png" plot="" i=0
+    for m in "${metrics[@]}"; do
+      plot+="set title '${m//\'/}'; plot '${csv}' using (strcol(3) eq '${m//\'/}' ? \$2 / 60.0 : NaN):4 with lines lw 2 notitle; "
+      i=$(( i + 1 ))
+    done
+    gnuplot -e "set terminal pngcairo size 800,$(( 220 * i )); set output '${out}'; set datafile separator ','; \
+      set key off; set grid; set xlabel 'minutes'; set multiplot layout ${i},1; ${plot} unset multiplot" \
+      >/dev/null 2>&1 && [[ -s "$out" ]] && printf '%s' "$out"
+    return
+  fi
+  local out="${RUN_TMP}/${name}-metrics.svg"
+  awk -F, -v label="$LABEL" '
+    function esc(s) { gsub(/&/, "\\&amp;", s); gsub(/</, "\\&lt;", s); return s }
+    $4 ~ /^-?[0-9.]+([eE][+-]?[0-9]+)?$/ {
+      if (!($3 in n)) order[++k] = $3
+      i = ++n[$3]; x[$3, i] = $2; y[$3, i] = $4 + 0
+      if (i == 1 || $4 + 0 < lo[$3]) lo[$3] = $4 + 0
+      if (i == 1 || $4 + 0 > hi[$3]) hi[$3] = $4 + 0
+      if ($2 + 0 > tmax) tmax = $2 + 0
+    }
+    END {
+      w = 800; h = 180; pad = 50
+      printf "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"%d\" height=\"%d\" font-family=\"sans-serif\" font-size=\"12\">\n", w, h * k + 30
+      printf "<rect width=\"100%%\" height=\"100%%\" fill=\"white\"/>\n"
+      printf "<text x=\"%d\" y=\"20\" font-size=\"14\">%s</text>\n", pad, esc(label)
+      for (p = 1; p <= k; p++) {
+        m = order[p]; top = 30 + (p - 1) * h + 20; bottom = top + h - 50
+        span = hi[m] - lo[m]; if (span == 0) span = 1
+        printf "<text x=\"%d\" y=\"%d\">%s (last %s)</text>\n", pad, top - 6, esc(m), y[m, n[m]]
+        printf "<rect x=\"%d\" y=\"%d\" width=\"%d\" height=\"%d\" fill=\"none\" stroke=\"#ccc\"/>\n", pad, top, w - 2 * pad, bottom - top
+        printf "<text x=\"%d\" y=\"%d\" text-anchor=\"end\" fill=\"#666\">%g</text>\n", pad - 4, top + 10, hi[m]
+        printf "<text x=\"%d\" y=\"%d\" text-anchor=\"end\" fill=\"#666\">%g</text>\n", pad - 4, bottom, lo[m]
+        printf "<polyline fill=\"none\" stroke=\"#1f77b4\" stroke-width=\"2\" points=\""
+        for (i = 1; i <= n[m]; i++)
+          printf "%.1f,%.1f ", pad + (tmax ? x[m, i] / tmax : i / n[m]) * (w - 2 * pad), bottom - (y[m, i] - lo[m]) / span * (bottom - top)
+        printf "\"/>\n"
+      }
+      printf "<text x=\"%d\" y=\"%d\" text-anchor=\"end\" fill=\"#666\">%s</text>\n", w - pad, h * k + 24, "elapsed " int(tmax / 60) "m"
+      print "</svg>"
+    }' "$csv" >"$out" && printf '%s' "$out"
+}
+
+# Passes lines through, appending "<pattern index> <line>" to RUN_TMP/alerts
+# for every line that matches an --alert-on pattern (checked without ANSI
+# codes). check_alerts turns these into notifications.
+alert_stage() {
+  if (( ${#ALERT_PATTERNS[@]} == 0 )); then
+    cat
+    return
+  fi
+  "${STREAM_AWK[@]}" -v pats="$(printf '%s\n' "${ALERT_PATTERNS[@]}")" -v streams="${ALERT_STREAMS[*]}" \
+    -v f="${RUN_TMP}/alerts" '
+    BEGIN { n = split(pats, re, "\n"); split(streams, st, " ") }
+    {
+      print; fflush()
+      line = $0
+      gsub(/\033\[[0-9;?]*[ -\/]*[@-~]/, "", line)
+      for (i = 1; i <= n; i++) if (st[i] != "stderr" && line ~ re[i]) { print (i - 1), line >> f; fflush(f); break }
+    }'
+}
+
+# With --split-streams the child's stderr passes through here on its way to
+# the output pipeline: the latest TAIL_BUFFER bytes are kept in RUN_TMP/stderr
+# (rotated like buffer_stage) and "stderr:" --alert-on patterns are checked.
+stderr_stage() {
+  "${STREAM_AWK[@]}" -v cap="$TAIL_BUFFER" -v f="${RUN_TMP}/stderr" -v a="${RUN_TMP}/alerts" \
+    -v pats="$(printf '%s\n' "${ALERT_PATTERNS[@]}")" -v streams="${ALERT_STREAMS[*]}" '
+    BEGIN { n = split(pats, re, "\n"); split(streams, st, " ") }
+    {
+      print; fflush(); print > f; fflush(f); size += length($0) + 1
+      line = $0
+      gsub(/\033\[[0-9;?]*[ -\/]*[@-~]/, "", line)
+      for (i = 1; i <= n; i++) if (st[i] == "stderr" && line ~ re[i]) { print (i - 1), line >> a; fflush(a); break }
+    }
+    size >= cap { close(f); system("mv -f \"" f "\" \"" f ".old\""); size = 0 }'
+}
+
+# Prefixes each line with its UTC time.
+stamp_lines() {
+  local -x TZ=UTC
+  local line
+  while IFS= read -r line || [[ -n "$line" ]]; do
+    printf '%(%Y-%m-%dT%H:%M:%SZ)T %s\n' -1 "$line"
+  done
+}
+
+# Appends lines to LOG_PATH, with --log-timestamps prefixes and
+# --log-max-size rotation (<path>.1 is the most recent rotated file).
+# Timestamps come from awk's strftime where it has one (gawk, mawk) and
+# from stamp_lines otherwise (BSD and busybox awk).
+log_stage() {
+  if [[ -z "$LOG_TIMESTAMPS" && -z "$LOG_MAX_SIZE" ]]; then
+    cat >>"$LOG_PATH"
+    return
+  fi
+  if [[ -n "$LOG_TIMESTAMPS" ]] && ! awk 'BEGIN { strftime("%s", 0, 1) }' >/dev/null 2>&1; then
+    stamp_lines | LOG_TIMESTAMPS="" log_stage
+    return
+  fi
+  local size=0
+  if [[ -f "$LOG_PATH" ]]; then
+    size="$(wc -c <"$LOG_PATH")"
+  fi
+  "${STREAM_AWK[@]}" -v f="$LOG_PATH" -v ts="$LOG_TIMESTAMPS" -v cap="${LOG_MAX_SIZE:-0}" -v keep="$LOG_KEEP" -v size="$size" '
+    function q(s) { gsub(/\047/, "\047\\\047\047", s); return "\047" s "\047" }
+    function rotate(  i, cmd) {
+      close(f)
+      cmd = ""
+      for (i = keep - 1; i >= 1; i--) cmd = cmd "[ ! -e " q(f "." i) " ] || mv -f " q(f "." i) " " q(f "." (i + 1)) "; "
+      system(cmd "mv -f " q(f) " " q(f ".1"))
+      size = 0
+    }
+    {
+      line = ts ? strftime("%Y-%m-%dT%H:%M:%SZ", systime(), 1) " " $0 : $0
+      print line >> f; fflush(f)
+      size += length(line) + 1
+      if (cap && size >= cap) rotate()
+    }'
+}
+
+# Passes lines through while keeping the most recent TAIL_BUFFER bytes in
+# RUN_TMP/recent: once it is full it becomes recent.old and a new one starts,
+# so memory and disk use stay bounded however chatty the job is.
+# ::ocnotify:: reports are left out.
+buffer_stage() {
+  "${STREAM_AWK[@]}" -v cap="$TAIL_BUFFER" -v f="${RUN_TMP}/recent" '
+    { print; fflush() }
+    /^[ \t]*::ocnotify::/ { next }
+    { print > f; fflush(f); size += length($0) + 1 }
+    size >= cap { close(f); system("mv -f \"" f "\" \"" f ".old\""); size = 0 }'
+}
+
+# Progress bars (tqdm, pip, --pty output) redraw one line with bare CRs and
+# terminals end lines with CRLF. CRLF becomes a plain line break and every
+# bare CR ends a line of its own, so each redraw reaches the parsers, tails
+# and stall detection as it happens; stdout and the log keep the raw bytes.
+# Without perl, tr is used instead, which also squeezes out blank lines.
+cr_stage() {
+  if command -v perl >/dev/null 2>&1; then
+    perl -e '$| = 1; my $cr = 0;
+      while (sysread(STDIN, my $b, 65536)) {
+        $b =~ s/^\n// if $cr; $cr = $b =~ /\r\z/; $b =~ s/\r\n?/\n/g; print $b
+      }'
+  elif command -v stdbuf >/dev/null 2>&1; then
+    stdbuf -o0 tr -s '\r\n' '\n'
+  else
+    tr -s '\r\n' '\n'
+  fi
+}
+
+# Passes lines through while recording the time of the latest output and
+# the latest line in RUN_TMP/activity (at most once a second, plus once
+# after a quiet second so the final line is never lost).
+watch_stage() {
+  if [[ -z "$STALL_AFTER" && -z "$HEARTBEAT" ]]; then
+    cat
+    return
+  fi
+  local line partial="" pending="" now last_write=0 status
+  while true; do
+    if IFS= read -r -t 1 line; then
+      printf '%s\n' "$line"
+      line="${partial}${line}"
+      partial=""
+      printf -v now '%(%s)T' -1
+      if (( now != last_write )); then
+        printf '%s %s\n' "$now" "$line" >"${RUN_TMP}/activity"
+        last_write="$now"
+        pending=""
+      else
+        pending="$line"
+      fi
+    else
+      status=$?
+      if (( status > 128 )); then
+        # Timed out: keep any partial line, flush the latest full one.
+        if [[ -n "$line" ]]; then
+          printf '%s' "$line"
+          partial+="$line"
+          printf -v last_write '%(%s)T' -1
+          pending="$partial"
+        fi
+        if [[ -n "$pending" ]]; then
+          printf '%s %s\n' "$last_write" "$pending" >"${RUN_TMP}/activity"
+          pending=""
+        fi
+        continue
+      fi
+      if [[ -n "$line" ]]; then
+        printf '%s' "$line"
+      fi
+      if [[ -n "$pending" ]]; then
+        printf '%s %s\n' "$last_write" "$pending" >"${RUN_TMP}/activity"
+      fi
+      break
+    fi
+  done
+}
+
+# Copies raw output to TEE_TARGET. A reader that goes away must not take the
+# pipeline down, so SIGPIPE is ignored and GNU tee told to keep going.
+tee_stage() {
+  if [[ -z "$TEE_TARGET" ]]; then
+    cat
+    return
+  fi
+  local flags=(-a)
+  if tee --version 2>/dev/null | grep -q GNU; then
+    flags+=(--output-error=warn-nopipe)
+  fi
+  (trap '' PIPE; tee "${flags[@]}" "$TEE_TARGET" 2>/dev/null)
+}
+
+dedupe_stage() {
+  if [[ -n "$DEDUPE_LINES" ]]; then
+    collapse_repeats
+  else
+    cat
+  fi
+}
+
+# Keeps every SAMPLE_EVERYth line ahead of the parsing stages, so they only
+# see the sample. Lines matching --sample-keep or an --alert-on pattern and
+# ::ocnotify:: reports always pass.
+sample_stage() {
+  if [[ -n "$SAMPLE_EVERY" ]]; then
+    "${STREAM_AWK[@]}" -v n="$SAMPLE_EVERY" -v keep="$SAMPLE_KEEP" -v pats="$(printf '%s\n' "${ALERT_PATTERNS[@]}")" '
+      BEGIN { np = split(pats, re, "\n") }
+      function wanted(  line, i) {
+        if ((NR - 1) % n == 0 || $0 ~ /^[ \t]*::ocnotify::/) return 1
+        line = $0
+        gsub(/\033\[[0-9;?]*[ -\/]*[@-~]/, "", line)
+        if (tolower(line) ~ keep) return 1
+        for (i = 1; i <= np; i++) if (re[i] != "" && line ~ re[i]) return 1
+        return 0
+      }
+      wanted() { print; fflush() }'
+  else
+    cat
+  fi
+}
+
+# Removes the run's temporary files, here and (cleanup_host) on the job host.
+cleanup_run() {
+  rm -rf "$RUN_TMP" "$RUN_DIR" ${SH_STATUS:+"$SH_STATUS"} ${CGROUP_STATS:+"$CGROUP_STATS"}
+  cleanup_host
+}
+
+# Runs "$@" in the background with its output fed through output_pipeline,
+# ticking the monitor until it exits, and again on failure as --retries
+# allows. Sets RC to the last attempt's exit code.
+run_monitored() {
+  RUN_TMP="$(mktemp -d "${TMPDIR:-/tmp}/ocnotify.XXXXXX")"
+  RUN_DIR="${OCNOTIFY_RUNTIME_DIR}/${RUN_ID}"
+  DECISION_FILE="${DECISION_FILE:-${RUN_DIR}/decision}"
+  trap cleanup_run EXIT
+  (umask 077; mkdir -p "$RUN_DIR")
+  # Background jobs get /dev/null as stdin; the child reads the wrapper's
+  # through this copy instead, so piped input and redirects still reach it.
+  exec {CHILD_IN}<&0
+  attempt=1
+  ATTEMPTS=()
+  local backoff="$RETRY_BACKOFF" restarts=() looping=""
+  while :; do
+    run_attempt "$@"
+    if (( attempt > RETRIES )) || ! should_retry; then
+      break
+    fi
+    ATTEMPTS+=("$(attempt_text)")
+    if [[ -n "$SERVICE" ]]; then
+      if (( end_epoch - start_epoch >= SERVICE_STABLE_SEC )); then
+        backoff="$RETRY_BACKOFF"
+      fi
+      restarts+=("$end_epoch")
+      if [[ -z "$looping" ]] && (( ${#restarts[@]} >= SERVICE_LOOP_RESTARTS )) \
+          && (( end_epoch - restarts[-SERVICE_LOOP_RESTARTS] < SERVICE_LOOP_WINDOW )); then
+        looping=1
+        event_begin crashloop "💥 ${LABEL}${HOST_SUFFIX} is crash-looping: ${SERVICE_LOOP_RESTARTS} exits within $(fmt_duration "$SERVICE_LOOP_WINDOW")"
+        event_tail "$(TAIL_LINES=10 output_tail)"
+        notify
+      fi
+    fi
+    event_begin retry "🔁 ${LABEL}${HOST_SUFFIX}: attempt ${attempt}/$(( RETRIES + 1 )) $(attempt_status) (exit=${RC}), attempt $(( attempt + 1 )) starts in $(fmt_duration "$backoff")"
+    event_field Elapsed "$(fmt_duration $(( end_epoch - start_epoch )))"
+    event_tail "$(TAIL_LINES=10 output_tail)"
+    notify
+    # In the background so a signal is handled right away, not after it.
+    sleep "$backoff" &
+    BACKOFF_PID=$!
+    wait "$BACKOFF_PID"
+    BACKOFF_PID=""
+    backoff=$(( backoff * 2 ))
+    attempt=$(( attempt + 1 ))
+    find "$RUN_TMP" -mindepth 1 -delete
+    rm -f "${RUN_DIR}/progress.fifo"
+  done
+}
+
+# Rewrites RUN_DIR/status.json (see the header) when anything in it changed.
+write_status() {
+  local state=running percent=null current=null total=null summary=null phase=null progress=null body
+  if [[ -n "$stalled_since" ]]; then
+    state=stalled
+  fi
+  if [[ -n "$PROGRESS_PERCENT" ]]; then
+    percent="$(json_number "$PROGRESS_PERCENT")"
+  fi
+  if [[ -n "$PROGRESS_CURRENT" && -n "$PROGRESS_TOTAL" ]]; then
+    current="$(json_number "$PROGRESS_CURRENT")"
+    total="$(json_number "$PROGRESS_TOTAL")"
+  fi
+  if [[ -n "$PROGRESS_SUMMARY" ]]; then
+    summary="$(json_str "$PROGRESS_SUMMARY")"
+  fi
+  if (( phase_index > 0 )); then
+    phase="$(json_str "${PHASES[$(( phase_index - 1 ))]}")"
+  fi
+  if progress="$(progress_text)"; then
+    progress="$(json_str "$progress")"
+  else
+    progress=null
+  fi
+  body="${STATUS_STATIC},\"state\":\"${state}\",\"attempt\":${attempt},\"percent\":${percent},\"current\":${current},\"total\":${total},\"summary\":${summary},\"phase\":${phase},\"progress\":${progress}"
+  [[ "$body" != "$status_last" ]] || return 0
+  status_last="$body"
+  printf '{%s,"updated":%s}\n' "$body" "$(json_str "$(now_iso)")" >"${RUN_DIR}/status.json.tmp" \
+    && mv -f "${RUN_DIR}/status.json.tmp" "${RUN_DIR}/status.json"
+}
+
+# Whether the attempt that just ended should be retried (see --retry-on).
+should_retry() {
+  if [[ -z "$timed_out" && "$RC" -eq 0 && -z "$SERVICE" ]]; then
+    return 1
+  fi
+  if [[ -n "$RETRY_CODES" ]]; then
+    [[ "$RETRY_CODES" == *",${RC},"* ]]
+  elif [[ -n "$RETRY_RE" ]]; then
+    TAIL_LINES=200 output_tail | grep -Eq -- "$RETRY_RE"
+  fi
+}
+
+attempt_status() {
+  if [[ -n "$timed_out" ]]; then
+    echo "timed out"
+  elif [[ -n "$SERVICE" ]]; then
+    echo exited
+  elif [[ "$RC" -ne 0 ]]; then
+    echo failed
+  else
+    echo completed
+  fi
+}
+
+# "2: failed (exit=1) after 3m 2s" for the attempt that just ended.
+attempt_text() {
+  printf '%s: %s (exit=%s) after %s' "$attempt" "$(attempt_status)" "$RC" "$(fmt_duration $(( end_epoch - start_epoch )))"
+}
+
+# One run of "$@" for run_monitored.
+run_attempt() {
+  budget_alerted=""
+  preempt_seen=""
+  next_preempt_check=0
+  next_io_sample=0
+  next_battery_check=0
+  next_progress_poll=0
+  next_count_poll=0
+  next_parser_run="$PARSER_CMD_EVERY"
+  heartbeat_gap="$HEARTBEAT"
+  next_heartbeat=""
+  if [[ -n "$HEARTBEAT" ]]; then
+    next_heartbeat="$(jittered "$HEARTBEAT")"
+  fi
+  parser_offset=0
+  parser_failures=0
+  parser_error=""
+  on_battery=""
+  battery_low=""
+  next_gist_update="$LIVE_GIST_EVERY"
+  gist_last=""
+  alerts_seen=0
+  next_level_check=0
+  ready_sent=""
+  next_ready_poll=0
+  next_probe="$PROBE_EVERY"
+  probe_checks=0
+  probe_fails=0
+  probe_streak=0
+  probe_ok=""
+  probe_down=""
+  level_count=""
+  level_minutes=0
+  level_total=0
+  level_spiking=""
+  notes_seen=0
+  progress_seen=0
+  milestone_sent=0
+  thresholds_percent=""
+  PROGRESS_PERCENT=""
+  PROGRESS_CURRENT=""
+  PROGRESS_TOTAL=""
+  PROGRESS_SUMMARY=""
+  PROGRESS_PEAK=""
+  PROGRESS_RATE=""
+  PROGRESS_RATE_AT=""
+  PROGRESS_RATE_FROM=""
+  SOURCE_PERCENT=() SOURCE_SUMMARY=() SOURCE_CURRENT=() SOURCE_TOTAL=()
+  phase_index=0
+  STEP_EXITS=()
+  JOB_PERCENT=() JOB_EXIT=() JOB_END=()
+  PHASE_SEEN=()
+  PHASE_SEEN_AT=()
+  ARTIFACTS=()
+  APPROVAL_PENDING=""
+  thresholds_checked=0
+  best_checked=0
+  thresholds_recheck=0
+  THRESHOLD_FIRED=()
+  ALERT_NEXT=()
+  ALERT_MISSED=()
+  stalled_since=""
+  timed_out=""
+  kill_sent=""
+  RC=""
+  start_iso="$(now_iso)"
+  start_epoch="$(date +%s)"
+  if (( ${#PHASES[@]} > 0 )); then
+    set_phase "${PHASES[0]}" "$start_epoch"
+  fi
+  printf '%s\n' "label=${LABEL}" "host=${HOST_SUFFIX# on }" "pid=$$" "start=${start_iso}" \
+    "tmp=${RUN_TMP}" "command=${*//$'\n'/ }" >"${RUN_DIR}/info"
+  local tty host=null log=null
+  tty="$(ps -o tty= -p $$ 2>/dev/null | tr -d ' ')" || tty=""
+  if [[ -n "$tty" && "$tty" != "?" ]]; then
+    tty="$(json_str "/dev/${tty}")"
+  else
+    tty=null
+  fi
+  if [[ -n "$HOST_SUFFIX" ]]; then
+    host="$(json_str "${HOST_SUFFIX# on }")"
+  fi
+  if [[ -n "$LOG_PATH" ]]; then
+    log="$(json_str "$LOG_PATH")"
+  fi
+  STATUS_STATIC="$(printf '"version":1,"run_id":%s,"label":%s,"host":%s,"pid":%s,"command":%s,"cwd":%s,"tty":%s,"start":%s,"start_epoch":%s,"log":%s' \
+    "$(json_str "$RUN_ID")" "$(json_str "$LABEL")" "$host" "$$" "$(json_str "$*")" "$(json_str "$PWD")" \
+    "$tty" "$(json_str "$start_iso")" "$start_epoch" "$log")"
+  status_last=""
+  resolve_parsers "$@"
+  mkfifo "${RUN_TMP}/output"
+  output_pipeline <"${RUN_TMP}/output" &
+  READER_PID=$!
+  # The child gets its own process group so timeouts and forwarded signals
+  # reach everything it spawned. setsid also takes away the controlling
+  # terminal, which password prompts (sudo, ssh, git) need, so a child run
+  # from a terminal stays in the wrapper's group and only it is signalled.
+  # Background jobs start with SIGINT ignored; restore it so Ctrl-C reaches
+  # the child.
+  local pgroup=()
+  CHILD_PGROUP=""
+  CHILD_ON_TTY=""
+  if [[ -t 0 ]]; then
+    CHILD_ON_TTY=1
+  elif command -v setsid >/dev/null 2>&1; then
+    pgroup=(setsid)
+    CHILD_PGROUP="-"
+  fi
+  (
+    trap - INT
+    if [[ -n "$SPLIT_STREAMS" ]]; then
+      exec 2> >(stderr_stage >"${RUN_TMP}/output")
+    fi
+    if [[ -z "$REMOTE_CHILD" ]]; then
+      mkfifo -m 600 "${RUN_DIR}/progress.fifo"
+      local child="$BASHPID"
+      (bash -c "$PROGRESS_FIFO_READER" ocnotify-progress "${RUN_DIR}/progress.fifo" "$child" &)
+      export OCNOTIFY_RUN_ID="$RUN_ID" OCNOTIFY_LABEL="$LABEL" OCNOTIFY_PROGRESS_FIFO="${RUN_DIR}/progress.fifo" \
+        OCNOTIFY_DECISION_FILE="$DECISION_FILE"
+      if [[ -n "$LOG_PATH" ]]; then
+        export OCNOTIFY_LOG_FILE="$LOG_PATH"
+      fi
+    fi
+    exec "${pgroup[@]}" "$@"
+  ) <&"$CHILD_IN" >"${RUN_TMP}/output" 2>&1 &
+  CHILD_PID=$!
+  CHILD_RC=""
+  declare -gA BEST_ALLTIME BEST_WHEN BEST_SENT
+  load_best
+  overdue_sent=""
+  load_profile
+  trap 'on_signal INT' INT
+  trap 'on_signal TERM' TERM
+  trap 'on_signal HUP' HUP
+  if [[ -n "$KEEP_AWAKE" ]] && (( attempt == 1 )); then
+    start_keep_awake
+  fi
+  if [[ -n "$LIVE_GIST" ]] && (( attempt == 1 )); then
+    start_live_gist "$@"
+  elif [[ -n "$NOTIFY_START" ]] && (( attempt == 1 )); then
+    send_started "$@"
+  fi
+  while kill -0 "$CHILD_PID" 2>/dev/null; do
+    monitor_tick
+    write_status
+    # bash reports a child killed by a signal after the next command that
+    # waits; most deaths land in this sleep, so keep that report quiet.
+    { sleep 1; } 2>/dev/null
+  done
+  set +e
+  reap_child
+  RC="$CHILD_RC"
+  finish_attempt
+  set -e
+}
+
+# Wraps up an attempt once its child has been reaped: reads the rest of its
+# output, last progress and alerts, and sets end_iso and end_epoch.
+finish_attempt() {
+  check_orphans
+  drain_reader
+  if [[ -n "$PARSER_CMD" ]]; then
+    run_parser_cmd
+  fi
+  if [[ -s "${RUN_TMP}/progress" ]]; then
+    check_progress
+  fi
+  if (( ${#ALERT_PATTERNS[@]} > 0 )); then
+    check_alerts
+  fi
+  end_iso="$(now_iso)"
+  end_epoch="$(date +%s)"
+}
+
+# Runs shell code $1 on the job host once per process still carrying this
+# run's OCNOTIFY_RUN_ID, with the PID in $p.
+for_run_procs() {
+  host_sh "for e in /proc/[0-9]*/environ; do
+  p=\${e#/proc/}; p=\${p%/environ}
+  tr '\\0' '\\n' <\"\$e\" 2>/dev/null | grep -qx 'OCNOTIFY_RUN_ID=${RUN_ID}' || continue
+  $1
+done; true" 2>/dev/null
+}
+
+# Lists "<pid> <name>" for the run's processes.
+orphan_probe() {
+  for_run_procs 'echo "$p $(cat /proc/$p/comm 2>/dev/null)"'
+}
+
+# Lists "<pid> <read_bytes> <write_bytes>" for the run's processes.
+io_probe() {
+  for_run_procs 'echo "$p" $(sed -n "s/^\(read\|write\)_bytes: //p" /proc/$p/io 2>/dev/null)'
+}
+
+# Records the latest IO counters of each process in the child tree; the
+# totals keep the last sample of processes that have since exited.
+sample_io() {
+  local pid r w
+  while read -r pid r w; do
+    if [[ -n "$w" ]]; then
+      IO_READ[$pid]="$r"
+      IO_WRITE[$pid]="$w"
+    fi
+  done < <(io_probe)
+}
+
+# "1.2 GiB read, 300.0 MiB written (4.1 MiB/s, 1.0 MiB/s)" over ELAPSED
+# seconds, from the --io-stats samples.
+io_summary() {
+  local elapsed="$1" pid read=0 written=0
+  for pid in "${!IO_READ[@]}"; do
+    read=$(( read + IO_READ[$pid] ))
+    written=$(( written + IO_WRITE[$pid] ))
+  done
+  if (( elapsed < 1 )); then
+    elapsed=1
+  fi
+  printf '%s read, %s written (%s/s, %s/s)' "$(fmt_bytes "$read")" "$(fmt_bytes "$written")" \
+    "$(fmt_bytes $(( read / elapsed )))" "$(fmt_bytes $(( written / elapsed )))"
+}
+
+# Waits until the reader has seen EOF on the output pipe and written
+# everything it keeps. Background processes that still hold the pipe would
+# keep it open forever, so after a grace period those writers are stopped;
+# the reader itself is always left to finish.
+drain_reader() {
+  local i pids
+  for i in {1..50}; do
+    kill -0 "$READER_PID" 2>/dev/null || break
+    sleep 0.1
+  done
+  if kill -0 "$READER_PID" 2>/dev/null; then
+    pids="$(pipe_writers "${RUN_TMP}/output")"
+    if [[ -n "$pids" ]]; then
+      kill -TERM $pids 2>/dev/null || true
+      sleep 1
+      kill -KILL $pids 2>/dev/null || true
+    fi
+  fi
+  wait "$READER_PID" 2>/dev/null || true
+}
+
+# PIDs of processes that have FIFO open for writing, from /proc or lsof.
+pipe_writers() {
+  local fd pid flags
+  if [[ -d /proc/self/fdinfo ]]; then
+    for fd in /proc/[0-9]*/fd/*; do
+      [[ "$(readlink "$fd" 2>/dev/null)" == "$1" ]] || continue
+      pid="${fd#/proc/}" pid="${pid%%/*}"
+      flags="$(sed -n 's/^flags:[[:space:]]*//p' "/proc/${pid}/fdinfo/${fd##*/}" 2>/dev/null)" || continue
+      if (( ${flags:-0} % 4 != 0 && pid != $$ )); then
+        echo "$pid"
+      fi
+    done | sort -u
+  elif command -v lsof >/dev/null 2>&1; then
+    lsof -Fpa -- "$1" 2>/dev/null | awk -v self=$$ '
+      /^p/ { pid = substr($0, 2) }
+      /^a[wu]/ && pid != self { print pid }' | sort -u
+  fi
+}
+
+# Called once the child has exited: notes background processes it left
+# behind in ORPHANS and, with --reap-orphans, stops them.
+check_orphans() {
+  local found pids names
+  found="$(orphan_probe)" || return 0
+  if [[ -z "$found" ]]; then
+    return 0
+  fi
+  pids="$(awk '{ printf "%s%s", sep, $1; sep = " " }' <<<"$found")"
+  names="$(awk '{ $1 = ""; sub(/^ /, ""); if (!seen[$0]++) printf "%s%s", sep, $0; sep = ", " }' <<<"$found")"
+  local n
+  n="$(wc -l <<<"$found")"
+  if [[ -n "$REAP_ORPHANS" ]]; then
+    host_sh "kill -TERM ${pids} 2>/dev/null
+for i in 1 2 3 4 5; do
+  alive=; for p in ${pids}; do kill -0 \$p 2>/dev/null && alive=1; done
+  [ -n \"\$alive\" ] || break; sleep 1
+done
+kill -KILL ${pids} 2>/dev/null; true" >/dev/null 2>&1 || true
+    ORPHANS="reaped ${n} background process$( (( n > 1 )) && echo es): ${names}"
+  else
+    ORPHANS="${n} background process$( (( n > 1 )) && echo es) still running: ${names}"
+  fi
+}
+
+# Bash prelude for --sh: pipefail, plus a record of the last commands run
+# and the final PIPESTATUS written to $OCNOTIFY_SH_STATUS on exit, so a
+# failure can be pinned on the segment that caused it.
+SH_PRELUDE='set -o pipefail
+__oc_hist=()
+trap '"'"'[[ "${__oc_hist[*]: -1}" == "$BASH_COMMAND" ]] || __oc_hist+=("$BASH_COMMAND")
+(( ${#__oc_hist[@]} <= 32 )) || __oc_hist=("${__oc_hist[@]: -16}")'"'"' DEBUG
+trap '"'"'__oc_ps=("${PIPESTATUS[@]}"); trap - DEBUG
+{ echo "${__oc_ps[*]}"; printf "%s\n" "${__oc_hist[@]: -${#__oc_ps[@]}}"; } >"$OCNOTIFY_SH_STATUS"'"'"' EXIT
+'
+
+# "Failed segment" text from the --sh status file: each non-zero pipeline
+# member with its exit code.
+sh_failed_segments() {
+  local statuses segments=() i out=""
+  { read -r statuses; mapfile -t segments; } <"$SH_STATUS" || return 1
+  read -r -a statuses <<<"$statuses"
+  if (( ${#segments[@]} != ${#statuses[@]} )); then
+    return 1
+  fi
+  for i in "${!statuses[@]}"; do
+    if [[ "${statuses[$i]}" != 0 ]]; then
+      out+="${out:+; }\`${segments[$i]}\` (exit ${statuses[$i]})"
+    fi
+  done
+  [[ -n "$out" ]] && printf '%s' "$out"
+}
+
+# Copies JSON lines written to the FIFO in $1 to stdout as ::ocnotify::
+# progress lines while process $2 (the child) runs, then removes the FIFO.
+# Opening it read-write means writers never block and never see EOF. It is
+# started from a subshell so it is not a child of the command: script(1)
+# (--pty) waits for all its children, and would wait for it forever.
+PROGRESS_FIFO_READER="$(cat <<'EOF'
+exec 3<>"$1"
+while kill -0 "$2" 2>/dev/null; do
+  if IFS= read -r -t 1 line <&3; then
+    printf '::ocnotify::%s\n' "${line#::ocnotify::}"
+  fi
+done
+rm -f "$1"
+EOF
+)"
+
+# Runs "$@" inside the transient scope created for --cgroup and, once it
+# exits, writes the scope's exact totals to the file in $1 (the scope is
+# removed as soon as it is empty, so this has to happen from inside).
+# Signals are forwarded to the command the same way the wrapper does.
+CGROUP_RUNNER="$(cat <<'EOF'
+stats="$1"; shift
+( trap - INT; exec "$@" ) &
+p=$!
+for s in INT TERM HUP; do trap "kill -s $s $p 2>/dev/null" "$s"; done
+while :; do
+  wait "$p"; rc=$?
+  kill -0 "$p" 2>/dev/null || break
+done
+cg="/sys/fs/cgroup$(sed -n 's/^0:://p' /proc/self/cgroup)"
+{
+  awk '$1 == "usage_usec" { print "cpu_usec", $2 }' "$cg/cpu.stat"
+  [ -r "$cg/memory.peak" ] && echo "memory_peak $(cat "$cg/memory.peak")"
+  awk '$1 == "oom_kill" { print "oom_kill", $2 }' "$cg/memory.events"
+  awk '{ for (i = 2; i <= NF; i++) { split($i, kv, "="); t[kv[1]] += kv[2] } }
+    END { print "io_read", t["rbytes"] + 0; print "io_write", t["wbytes"] + 0 }' "$cg/io.stat"
+} >"$stats" 2>/dev/null
+exit "$rc"
+EOF
+)"
+
+# Runs the --step commands given as "<json name> <gated> <command>" triples
+# after the keep-going flag in $1. A gated step first asks for approval and
+# waits for the answer in $OCNOTIFY_DECISION_FILE; a rejection ends the
+# chain. Each step is announced as a phase (with its start time, as reports
+# are only read once per tick), and its exit status is reported with a
+# step_exit progress key. Exits with the first failing step's status.
+CHAIN_RUNNER="$(cat <<'EOF'
+keep="$1"; shift
+failed=0
+while (( $# >= 3 )); do
+  if [[ "$2" == 1 ]]; then
+    rm -f "$OCNOTIFY_DECISION_FILE"
+    printf '::ocnotify::{"approve":%s}\n' "$1"
+    until [[ -s "$OCNOTIFY_DECISION_FILE" ]]; do
+      sleep 1
+    done
+    if [[ "$(<"$OCNOTIFY_DECISION_FILE")" != approve ]]; then
+      echo "ocnotify: step not approved, stopping"
+      (( failed != 0 )) || failed=1
+      break
+    fi
+  fi
+  printf '::ocnotify::{"phase":%s,"phase_at":%(%s)T}\n' "$1" -1
+  rc=0
+  bash -c "$3" || rc=$?
+  printf '::ocnotify::{"step_exit":%d}\n' "$rc"
+  shift 3
+  if (( rc != 0 )); then
+    (( failed != 0 )) || failed="$rc"
+    [[ -n "$keep" ]] || break
+  fi
+done
+exit "$failed"
+EOF
+)"
+
+# Runs the --job commands given as "<json label> <label> <command>" triples
+# all at once. Output lines get a "[label] " prefix and progress reports a
+# "job" key; a job_exit report (with job_at, its end time) follows each
+# job. Exits with the first failing job's status.
+JOB_RUNNER="$(cat <<'EOF'
+pids=()
+while (( $# >= 3 )); do
+  (
+    bash -c "$3" 2>&1 | while IFS= read -r line; do
+      case "$line" in
+        *::ocnotify::\{*) printf '::ocnotify::{"job":%s,%s\n' "$1" "${line#*::ocnotify::\{}" ;;
+        *) printf '[%s] %s\n' "$2" "$line" ;;
+      esac
+    done
+    rc="${PIPESTATUS[0]}"
+    printf '::ocnotify::{"job":%s,"job_exit":%d,"job_at":%(%s)T}\n' "$1" "$rc" -1
+    exit "$rc"
+  ) &
+  pids+=($!)
+  shift 3
+done
+failed=0
+for pid in "${pids[@]}"; do
+  rc=0
+  wait "$pid" || rc=$?
+  (( failed != 0 )) || failed="$rc"
+done
+exit "$failed"
+EOF
+)"
+
+# Follows the --watch-file given in $1 from its first line, ending with
+# status 0 at the first line matching the ERE in $2, or once process $3 has
+# exited (status 1 if $2 was given and never matched).
+WATCH_RUNNER="$(cat <<'EOF'
+file="$1" until="$2" pid="$3"
+exec 3< <(exec tail -n +1 -F -- "$file" 2>/dev/null)
+tail_pid=$!
+if [[ -n "$pid" ]]; then
+  ( while kill -0 "$pid" 2>/dev/null; do sleep 5; done; kill "$tail_pid" 2>/dev/null ) &
+fi
+rc=0
+[[ -z "$until" ]] || rc=1
+while IFS= read -r line <&3; do
+  printf '%s\n' "$line"
+  if [[ -n "$until" && "$line" =~ $until ]]; then
+    rc=0
+    break
+  fi
+done
+kill "$tail_pid" $(jobs -p) 2>/dev/null
+exit "$rc"
+EOF
+)"
+
+# The command that runs the --step commands in order, the --job commands
+# side by side or the --watch-file runner, NUL-separated.
+batch_cmd() {
+  local step job args=()
+  if [[ -n "$WATCH_FILE" ]]; then
+    printf '%s\0' bash -c "$WATCH_RUNNER" ocnotify-watch "$WATCH_FILE" "$WATCH_UNTIL" "$WATCH_PID"
+    return
+  fi
+  if (( ${#JOBS[@]} > 0 )); then
+    for job in "${JOBS[@]}"; do
+      args+=("$(json_str "${job%%=*}")" "${job%%=*}" "${job#*=}")
+    done
+    printf '%s\0' bash -c "$JOB_RUNNER" ocnotify-jobs "${args[@]}"
+    return
+  fi
+  for step in "${STEPS[@]}"; do
+    args+=("$(json_str "$(step_name "$step")")" "$(step_gated "$step")" "$step")
+  done
+  printf '%s\0' bash -c "$CHAIN_RUNNER" ocnotify-chain "$KEEP_GOING" "${args[@]}"
+}
+
+# 1 if a step matches an --approve-before regex, 0 otherwise.
+step_gated() {
+  local re
+  for re in "${APPROVE_BEFORE[@]}"; do
+    if [[ "$1" =~ $re ]]; then
+      echo 1
+      return
+    fi
+  done
+  echo 0
+}
+
+# Phase name of a step: its command, shortened to 40 characters.
+step_name() {
+  if (( ${#1} > 40 )); then
+    printf '%s…' "${1:0:39}"
+  else
+    printf '%s' "$1"
+  fi
+}
+
+# "✓ make 1m 2s · ✗ make test (exit 1) 30s · – deploy (not run)" for the
+# --step commands, from the phases seen and their step_exit reports.
+steps_summary() {
+  local i out="" item until
+  for i in "${!STEPS[@]}"; do
+    item="${PHASES[$i]}"
+    if (( i < ${#PHASE_SEEN[@]} )); then
+      until="${PHASE_SEEN_AT[$(( i + 1 ))]:-$1}"
+      case "${STEP_EXITS[$i]:-}" in
+        0) item="✓ ${item}" ;;
+        "") item="✗ ${item} (interrupted)" ;;
+        *) item="✗ ${item} (exit ${STEP_EXITS[$i]})" ;;
+      esac
+      item+=" $(fmt_duration $(( until - PHASE_SEEN_AT[i] )))"
+    else
+      item="– ${item} (not run)"
+    fi
+    out+="${out:+ · }${item}"
+  done
+  printf '%s' "$out"
+}
+
+# Prefix for running the child in its own transient cgroup (systemd scope),
+# with the MemoryMax limit from --memory-max if given.
+cgroup_cmd() {
+  local cmd=(systemd-run --scope --quiet --collect)
+  if (( EUID != 0 )); then
+    cmd+=(--user)
+  fi
+  if [[ -n "$MEMORY_MAX" ]]; then
+    cmd+=(-p "MemoryMax=${MEMORY_MAX}" -p MemorySwapMax=0)
+  fi
+  printf '%s\0' "${cmd[@]}" -- bash -c "$CGROUP_RUNNER" ocnotify-cgroup "$CGROUP_STATS"
+}
+
+# "CPU 1h02m, peak memory 3.2 GiB, IO 1.1 GiB read / 200.0 MiB written"
+# from the --cgroup stats file.
+cgroup_summary() {
+  local -A st=()
+  local key value out=""
+  while read -r key value; do
+    st[$key]="$value"
+  done <"$CGROUP_STATS"
+  if [[ -n "${st[cpu_usec]:-}" ]]; then
+    out+="CPU $(fmt_duration $(( st[cpu_usec] / 1000000 )))"
+  fi
+  if [[ -n "${st[memory_peak]:-}" ]]; then
+    out+="${out:+, }peak memory $(fmt_bytes "${st[memory_peak]}")"
+  fi
+  if [[ -n "${st[io_read]:-}" ]]; then
+    out+="${out:+, }IO $(fmt_bytes "${st[io_read]}") read / $(fmt_bytes "${st[io_write]}") written"
+  fi
+  if (( ${st[oom_kill]:-0} > 0 )); then
+    out+="${out:+; }killed by the OOM killer at MemoryMax=${MEMORY_MAX:-?}"
+  fi
+  [[ -n "$out" ]] && printf '%s' "$out"
+}
+
+# Rule files for likely_cause: user rules first, then the shipped ones.
+CAUSE_FILES=("$(dirname "$OCNOTIFY_CONFIG")/causes.tsv" "${OCNOTIFY_DIR}/oc-notify-causes.tsv")
+
+# "explanation — hint" for the first cause rule that matches the output
+# tail; fails if none does.
+likely_cause() {
+  local files=() f
+  for f in "${CAUSE_FILES[@]}"; do
+    if [[ -f "$f" ]]; then
+      files+=("$f")
+    fi
+  done
+  if (( ${#files[@]} == 0 )); then
+    return 1
+  fi
+  TAIL_LINES=200 output_tail | awk -F '\t' -v nfiles="${#files[@]}" '
+    FILENAME != current { current = FILENAME; fileno++ }
+    fileno <= nfiles {
+      if ($0 !~ /^#/ && NF >= 2) { re[++n] = tolower($1); why[n] = $2; hint[n] = $3 }
+      next
+    }
+    { line = tolower($0); for (i = 1; i <= n && (!best || i < best); i++) if (line ~ re[i]) best = i }
+    END { if (!best) exit 1; printf "%s%s", why[best], (hint[best] != "" ? " — " hint[best] : "") }
+  ' "${files[@]}" -
+}
+
+# JSON body for the gists API with CONTENT as the live log file.
+gist_payload() {
+  printf '{"description":%s,"public":false,"files":{%s:{"content":%s}}}' \
+    "$(json_str "ocnotify live log: ${LABEL}${HOST_SUFFIX}")" \
+    "$(json_str "${LABEL//[^A-Za-z0-9._-]/_}.log")" "$(json_str "$1")"
+}
+
+# The ▶️ "started" message for command "$@": with --notify-start the host,
+# command and run id, and the live log link if there is one.
+send_started() {
+  if [[ -z "$NOTIFY_START" ]]; then
+    event_begin started "▶️ ${LABEL}${HOST_SUFFIX} started"
+  else
+    event_begin started "▶️ ${LABEL} started${HOST_SUFFIX:- on $(hostname)}"
+    event_field Command "$(cut -c1-300 <<<"${SH_CMD:-$*}")"
+    event_field Run "$RUN_ID"
+    if [[ -n "$TYPICAL_SEC" ]]; then
+      event_field Typical "$(fmt_duration "$TYPICAL_SEC") (median of the last ${TYPICAL_RUNS} successful runs)"
+    fi
+    if [[ -n "$LOG_PATH" ]]; then
+      event_field Log "$LOG_PATH"
+    fi
+  fi
+  if [[ -n "$GIST_URL" ]]; then
+    event_field "Live log" "$GIST_URL"
+  fi
+  notify
+}
+
+# Creates the --live-gist gist and announces its link. A failure only
+# costs the live log, never the run.
+start_live_gist() {
+  local created
+  if ! created="$(gist_payload "(no output yet)" \
+      | gh api gists --input - --jq '.id + " " + .html_url' 2>/dev/null)"; then
+    echo "ocnotify: could not create the live gist; continuing without it" >&2
+    if [[ -n "$NOTIFY_START" ]]; then
+      send_started "$@"
+    fi
+    return 0
+  fi
+  GIST_ID="${created%% *}"
+  GIST_URL="${created#* }"
+  send_started "$@"
+}
+
+# Pushes the current output tail to the live gist if it changed.
+update_live_gist() {
+  if [[ -z "$GIST_ID" ]]; then
+    return 0
+  fi
+  local content
+  content="$(TAIL_LINES=200 output_tail)"
+  if [[ -z "$content" || "$content" == "$gist_last" ]]; then
+    return 0
+  fi
+  gist_last="$content"
+  gist_payload "# updated $(now_iso)"$'\n'"${content}" \
+    | gh api -X PATCH "gists/${GIST_ID}" --input - >/dev/null 2>&1 || true
+}
+
+# Writes the last ATTACH_TAIL bytes of output to a file named after the
+# label and prints its path; fails if there was no output.
+tail_attachment() {
+  local name="${LABEL//[^A-Za-z0-9._-]/_}"
+  local path="${RUN_TMP}/${name}-tail.log"
+  cat "${RUN_TMP}/recent.old" "${RUN_TMP}/recent" 2>/dev/null | tail -c "$ATTACH_TAIL" \
+    | strip_ansi >"$path"
+  [[ -s "$path" ]] && printf '%s' "$path"
+}
+
+# Sends the single completion/failure message for the finished run.
+notify_done() {
+  local status="completed"
+  if [[ -n "$INTERRUPTED" ]]; then
+    status="interrupted"
+  elif [[ -n "$timed_out" ]]; then
+    status="timeout"
+  elif [[ "$RC" -ne 0 || -n "$SERVICE" ]]; then
+    status="failed"
+  fi
+  HISTORY_TEXT=""
+  if [[ "$status" == "completed" ]] && (( ${#HISTORY_METRICS[@]} > 0 )); then
+    check_history
+  fi
+  if [[ "$status" == "interrupted" ]]; then
+    event_begin interrupted "${INTERRUPTED} (exit=${RC})"
+  elif [[ "$status" == "timeout" ]]; then
+    local how="SIGTERM"
+    if [[ -n "$kill_sent" ]]; then
+      how="SIGKILL after ${KILL_AFTER}s grace"
+    fi
+    event_begin timeout "⏱️ ${LABEL}${HOST_SUFFIX} timed out after $(fmt_duration "$TIMEOUT"), stopped with ${how} (exit=${RC})"
+  elif [[ -n "$SERVICE" ]]; then
+    event_begin failed "💥 ${LABEL}${HOST_SUFFIX} exited unexpectedly after $(fmt_duration $(( end_epoch - start_epoch ))) (exit=${RC})"
+  else
+    event_begin "$status" "⚒️ ${LABEL}${HOST_SUFFIX} ${status} (exit=${RC})"
+  fi
+  event_field Start "${start_iso} UTC"
+  event_field End "${end_iso} UTC"
+  if [[ -n "$TYPICAL_SEC" ]]; then
+    event_field Duration "$(fmt_duration $(( end_epoch - start_epoch ))) (typically $(fmt_duration "$TYPICAL_SEC") over the last ${TYPICAL_RUNS} runs)"
+  fi
+  if [[ "$status" == "completed" && -n "$PROFILE" ]]; then
+    save_profile
+  fi
+  local progress rate
+  if [[ "$status" != "completed" ]] && progress="$(progress_text)"; then
+    event_field Progress "$progress"
+  fi
+  if rate="$(progress_rate 0)"; then
+    event_field Rate "$rate"
+  fi
+  if (( ${#JOBS[@]} > 0 )); then
+    event_field Jobs "$(jobs_summary)"
+  elif (( ${#STEPS[@]} > 0 )); then
+    event_field Steps "$(steps_summary "$end_epoch")"
+  elif (( ${#PHASE_SEEN[@]} > 0 )); then
+    event_field Phases "$(phase_summary "$end_epoch")"
+  fi
+  if [[ -n "$PROBE_TARGET" ]] && (( probe_checks > 0 )); then
+    event_field Probe "${PROBE_TARGET}: ${probe_fails}/${probe_checks} checks failed"
+  fi
+  if (( ${#APPROVALS[@]} > 0 )); then
+    event_field Approvals "$(printf '%s · ' "${APPROVALS[@]}" | sed 's/ · $//')"
+  fi
+  if (( ${#ATTEMPTS[@]} > 0 )); then
+    event_field Attempts "$(printf '%s; ' "${ATTEMPTS[@]}")$(attempt_text)"
+  fi
+  if [[ -n "$LOG_PATH" ]]; then
+    event_field Log "$LOG_PATH"
+  fi
+  if [[ -n "$GIST_URL" ]]; then
+    update_live_gist
+    event_field "Live log" "$GIST_URL"
+  fi
+  if [[ -n "$COST_RATE" ]]; then
+    event_field Cost "~\$$(cost_so_far $(( end_epoch - start_epoch ))) (at \$${COST_RATE}/h)"
+  fi
+  if [[ -n "$IO_STATS" ]]; then
+    event_field IO "$(io_summary $(( end_epoch - start_epoch )))"
+  fi
+  local resources
+  if [[ -s "$CGROUP_STATS" ]] && resources="$(cgroup_summary)"; then
+    event_field Resources "$resources"
+  fi
+  local metrics
+  if metrics="$(metric_summary)"; then
+    event_field Metrics "$metrics"
+  fi
+  local trend name
+  if name="$(primary_metric)" && trend="$(metric_sparkline "$name")"; then
+    event_field Trend "${name} ${trend}"
+  fi
+  local best
+  if (( ${#BEST_METRICS[@]} > 0 )) && best="$(best_summary)"; then
+    event_field Best "$best"
+  fi
+  if [[ -n "$HISTORY_TEXT" ]]; then
+    event_field History "$HISTORY_TEXT"
+  fi
+  if [[ -n "$ORPHANS" ]]; then
+    event_field Note "$ORPHANS"
+  fi
+  if (( ${#ARTIFACTS[@]} > 0 )); then
+    local joined
+    printf -v joined '%s, ' "${ARTIFACTS[@]}"
+    event_field Artifacts "${joined%, }"
+  fi
+  if (( parser_failures > 0 )); then
+    event_field Parser "--parser-cmd failed ${parser_failures} time(s), last: ${parser_error}"
+  fi
+  if [[ -n "$KEEP_AWAKE_FAILED" ]]; then
+    event_field Sleep "not inhibited (${KEEP_AWAKE_FAILED})"
+  fi
+  local notes
+  if notes="$(notes_summary)"; then
+    event_field Notes "$notes"
+  fi
+  local missed=0 i
+  for i in "${!ALERT_MISSED[@]}"; do
+    missed=$(( missed + ALERT_MISSED[$i] ))
+  done
+  if (( missed > 0 )); then
+    event_field Alerts "${missed} more --alert-on match(es) held back by the cooldown"
+  fi
+  if [[ -n "$SH_CMD" ]]; then
+    event_field Command "$SH_CMD"
+    local failed_at
+    if [[ "$status" == "failed" && -s "$SH_STATUS" ]] && failed_at="$(sh_failed_segments)"; then
+      event_field "Failed segment" "$failed_at"
+    fi
+  fi
+  local cause
+  if [[ "$status" == "failed" ]] && cause="$(likely_cause)"; then
+    event_field "Likely cause" "$cause"
+  fi
+  if [[ "$status" != "completed" ]]; then
+    add_host_context
+  fi
+  if [[ "$status" == "completed" && -n "$ON_SUCCESS" ]]; then
+    run_hook on-success "$ON_SUCCESS"
+  elif [[ "$status" != "completed" && -n "$ON_FAILURE" ]]; then
+    run_hook on-failure "$ON_FAILURE"
+  fi
+  if [[ -n "$HOOK_RESULTS" ]]; then
+    event_field Hooks "$HOOK_RESULTS"
+  fi
+  if [[ "$status" != "completed" && -n "$SPLIT_STREAMS" && -s "${RUN_TMP}/stderr" ]]; then
+    event_tail "$(TAIL_LINES=10 output_tail stderr)"
+  elif [[ "$status" != "completed" ]]; then
+    event_tail "$(TAIL_LINES=10 output_tail)"
+  fi
+  if [[ -n "$MIN_DURATION" ]] && (( end_epoch - start_epoch < MIN_DURATION )) \
+      && [[ "$status" == completed || -n "$MIN_DURATION_ALL" ]]; then
+    return 0
+  fi
+  local media=""
+  if [[ -n "$ATTACH_TAIL" && "$status" != "completed" ]]; then
+    media="$(tail_attachment)" || media=""
+  fi
+  if [[ -z "$media" && -n "$PLOT_PATH" ]]; then
+    media="$(host_fetch "$PLOT_PATH")" || media=""
+  fi
+  if [[ -z "$media" && -n "$PLOT_METRICS" ]]; then
+    media="$(plot_metrics)" || media=""
+  fi
+  notify "$media"
+  metrics_reported
+}
@@ -0,0 +1,354 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Control runs started by oc-run-notify.sh / oc-run-notify-ssh.sh on this
+# machine without restarting them.
+#
+# Usage:
+#   oc-notify-ctl.sh list
+#   oc-notify-ctl.sh submit [--ssh] <options> -- <cmd>  Start a detached run
+#   oc-notify-ctl.sh tmux-status                   One-line status of active runs
+#   oc-notify-ctl.sh waybar-json | xbar            The same for desktop bars
+#   oc-notify-ctl.sh forget <label>                Reset the label's typical duration
+#   oc-notify-ctl.sh <run> mute [dur]              Silence all but the final message
+#   oc-notify-ctl.sh <run> snooze <filter> [dur]   Silence matching events only
+#   oc-notify-ctl.sh <run> unmute                  Undo mute and snooze
+#   oc-notify-ctl.sh <run> note [--post] <text>    Add a timestamped note
+#   oc-notify-ctl.sh <run> status                  Progress, elapsed time and recent output
+#   oc-notify-ctl.sh <run> tail [lines]            Recent output (default 20 lines)
+#   oc-notify-ctl.sh <run> kill                    Stop the command and report it
+#   oc-notify-ctl.sh <run> approve | reject        Answer a ⏸️ approval request
+#
+# <run> is a run id from `list`, the wrapper PID or a unique label. <filter>
+# takes the same event names and classes as --sink (e.g. alert, stall,
+# warning; milestone matches progress updates). Without a duration the
+# silence lasts until unmute. Notes are listed in the final message; --post
+# also sends one right away.
+#
+# kill sends SIGTERM to the wrapper, which forwards it to the command
+# (SIGKILL after the --kill-after grace) and reports the cancellation.
+#
+# submit starts oc-run-notify.sh (oc-run-notify-ssh.sh with --ssh) with the
+# given options in the background, detached from the terminal, and prints
+# its PID, which works as <run>. Its own output goes to
+# $OCNOTIFY_STATE_DIR/submitted/. Settings shared by every run belong in
+# the config file ($OCNOTIFY_CONFIG), and `list` shows all watched runs.
+#
+# tmux-status prints e.g. "train 42% ~12m | ⚠ build 3m" (percent and time
+# left, or elapsed time before any progress; ⚠ marks a stalled run) and
+# nothing when no run is active:
+#   set -g status-right '#(oc-notify-ctl.sh tmux-status)'
+#   set -g status-interval 5
+# waybar-json prints a custom module object (text, tooltip with one line per
+# run, class idle/running/stalled, percentage):
+#   "custom/ocnotify": {"exec": "oc-notify-ctl.sh waybar-json",
+#                       "return-type": "json", "interval": 5}
+# xbar prints xbar/argos plugin output; link a script named e.g.
+# ocnotify.5s.sh that runs `oc-notify-ctl.sh xbar` into the plugin folder.
+#
+# Example:
+#   oc-notify-ctl.sh "snake 2000g" mute 2h
+
+source "$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)/oc-notify-common.sh"
+
+usage() {
+  sed -n '7,20s/^# //p' "${BASH_SOURCE[0]}" >&2
+  exit 2
+}
+
+# Prints the value of KEY from a run's info file.
+run_info() {
+  sed -n "s/^$2=//p" "$1/info" 2>/dev/null | head -n 1
+}
+
+list_runs() {
+  local dir pid state
+  printf '%-28s %-8s %-20s %-8s %s\n' RUN PID STARTED STATE LABEL
+  for dir in "$OCNOTIFY_RUNTIME_DIR"/*/; do
+    [[ -f "${dir}info" ]] || continue
+    pid="$(run_info "$dir" pid)"
+    state="running"
+    if ! kill -0 "$pid" 2>/dev/null; then
+      state="stale"
+    elif [[ -s "${dir}silence" ]]; then
+      state="muted"
+    fi
+    printf '%-28s %-8s %-20s %-8s %s\n' "$(basename "$dir")" "$pid" \
+      "$(run_info "$dir" start)" "$state" "$(run_info "$dir" label)$(run_info "$dir" host | sed 's/^./ on &/')"
+  done
+}
+
+# "12m", "1h05m" or "40s": fmt_duration without the seconds above a minute.
+short_duration() {
+  if (( $1 >= 3600 )); then
+    printf '%dh%02dm' $(( $1 / 3600 )) $(( $1 % 3600 / 60 ))
+  elif (( $1 >= 60 )); then
+    printf '%dm' $(( $1 / 60 ))
+  else
+    printf '%ds' "$1"
+  fi
+}
+
+# Prints "<label> <state> <percent> <seconds left> <elapsed> <progress>" for
+# each active run, from its status.json, separated by \x1f (tab would merge
+# the empty fields of unknown values).
+run_statuses() {
+  local file key value now elapsed left
+  local -A st
+  printf -v now '%(%s)T' -1
+  for file in "$OCNOTIFY_RUNTIME_DIR"/*/status.json; do
+    [[ -f "$file" ]] || continue
+    st=()
+    while IFS=$'\t' read -r key value; do
+      [[ "$value" == null ]] || st[$key]="$value"
+    done < <(json_fields "$(<"$file")")
+    kill -0 "${st[pid]:-0}" 2>/dev/null || continue
+    elapsed=$(( now - ${st[start_epoch]:-$now} ))
+    left=""
+    if [[ -n "${st[percent]:-}" ]] && awk -v p="${st[percent]}" 'BEGIN { exit !(p > 0 && p < 100) }'; then
+      left="$(awk -v p="${st[percent]}" -v e="$elapsed" 'BEGIN { printf "%d", e * (100 - p) / p }')"
+    fi
+    printf '%s\x1f%s\x1f%s\x1f%s\x1f%s\x1f%s\n' "${st[label]}" "${st[state]:-running}" "${st[percent]:-}" "$left" \
+      "$elapsed" "${st[progress]:-}"
+  done
+}
+
+# "⚠ train 42% ~12m": a run's short status, from run_statuses fields.
+run_brief() {
+  local label="$1" state="$2" percent="$3" left="$4" elapsed="$5" item
+  item="$label"
+  if [[ "$state" == stalled ]]; then
+    item="⚠ ${item}"
+  fi
+  if [[ -n "$percent" ]]; then
+    item+=" ${percent%.*}%"
+    if [[ -n "$left" ]]; then
+      item+=" ~$(short_duration "$left")"
+    fi
+  else
+    item+=" $(short_duration "$elapsed")"
+  fi
+  printf '%s' "$item"
+}
+
+# One line for the tmux status bar.
+tmux_status() {
+  local label state percent left elapsed progress out=""
+  while IFS=$'\x1f' read -r label state percent left elapsed progress; do
+    out+="${out:+ | }$(run_brief "$label" "$state" "$percent" "$left" "$elapsed")"
+  done < <(run_statuses)
+  printf '%s\n' "$out"
+}
+
+# A waybar custom module object: the tmux line as text, one line per run
+# in the tooltip, class idle, running or stalled, and the lowest percent.
+waybar_json() {
+  local label state percent left elapsed progress text="" tooltip="" class=idle lowest=""
+  while IFS=$'\x1f' read -r label state percent left elapsed progress; do
+    text+="${text:+ | }$(run_brief "$label" "$state" "$percent" "$left" "$elapsed")"
+    tooltip+="${tooltip:+$'\n'}${label}: ${progress:-running} ($(short_duration "$elapsed"))"
+    if [[ "$state" == stalled ]]; then
+      class=stalled
+    elif [[ "$class" == idle ]]; then
+      class=running
+    fi
+    if [[ -n "$percent" ]] && { [[ -z "$lowest" ]] || (( ${percent%.*} < lowest )); }; then
+      lowest="${percent%.*}"
+    fi
+  done < <(run_statuses)
+  printf '{"text":%s,"tooltip":%s,"class":%s,"percentage":%s}\n' "$(json_str "$text")" "$(json_str "$tooltip")" \
+    "$(json_str "$class")" "${lowest:-0}"
+}
+
+# xbar / argos plugin output: the tmux line in the menu bar (⚒ when idle),
+# then one menu item per run. "|" starts item parameters there, so it is
+# kept out of the text.
+xbar_status() {
+  local label state percent left elapsed progress bar="" items=()
+  while IFS=$'\x1f' read -r label state percent left elapsed progress; do
+    bar+="${bar:+ · }$(run_brief "$label" "$state" "$percent" "$left" "$elapsed")"
+    items+=("${label}: ${progress:-running} ($(short_duration "$elapsed"))")
+  done < <(run_statuses)
+  printf '%s\n---\n' "${bar:-⚒}"
+  if (( ${#items[@]} == 0 )); then
+    echo "No active runs"
+  else
+    printf '%s\n' "${items[@]//|/¦}"
+  fi
+}
+
+# Starts a wrapper detached with "$@" as its arguments (after an optional
+# --ssh) and prints its PID and output file; fails with that output if the
+# wrapper exits right away, e.g. on a bad option.
+submit_run() {
+  local wrapper="oc-run-notify.sh" dir="${OCNOTIFY_STATE_DIR}/submitted" out pid
+  if [[ "${1:-}" == --ssh ]]; then
+    wrapper="oc-run-notify-ssh.sh"
+    shift
+  fi
+  mkdir -p "$dir"
+  out="${dir}/$(date +%Y%m%d-%H%M%S)-${RANDOM}.out"
+  setsid nohup "${OCNOTIFY_DIR}/${wrapper}" "$@" </dev/null >"$out" 2>&1 &
+  pid=$!
+  sleep 0.5
+  if ! kill -0 "$pid" 2>/dev/null && ! wait "$pid"; then
+    cat "$out" >&2
+    exit 1
+  fi
+  printf 'pid %s, output %s\n' "$pid" "$out"
+}
+
+# Resolves a run id, wrapper PID or label to its runtime directory.
+find_run() {
+  local dir matches=()
+  if [[ -f "${OCNOTIFY_RUNTIME_DIR}/$1/info" ]]; then
+    printf '%s' "${OCNOTIFY_RUNTIME_DIR}/$1"
+    return
+  fi
+  for dir in "$OCNOTIFY_RUNTIME_DIR"/*; do
+    [[ -f "${dir}/info" ]] || continue
+    if [[ "$(run_info "$dir" pid)" == "$1" || "$(run_info "$dir" label)" == "$1" ]]; then
+      matches+=("$dir")
+    fi
+  done
+  if (( ${#matches[@]} == 0 )); then
+    echo "No run matches: $1" >&2
+    exit 1
+  elif (( ${#matches[@]} > 1 )); then
+    echo "Several runs match $1; use the run id from list" >&2
+    exit 1
+  fi
+  printf '%s' "${matches[0]}"
+}
+
+# Prints a run's state and progress from its status.json, and its recent
+# output.
+show_status() {
+  local dir="$1" key value now
+  local -A st
+  printf -v now '%(%s)T' -1
+  if [[ -f "${dir}/status.json" ]]; then
+    while IFS=$'\t' read -r key value; do
+      [[ "$value" == null ]] || st[$key]="$value"
+    done < <(json_fields "$(<"${dir}/status.json")")
+  fi
+  printf 'Run:      %s\n' "$(basename "$dir")"
+  printf 'Label:    %s\n' "$(run_info "$dir" label)$(run_info "$dir" host | sed 's/^./ on &/')"
+  printf 'Command:  %s\n' "$(run_info "$dir" command)"
+  printf 'PID:      %s\n' "$(run_info "$dir" pid)"
+  printf 'Started:  %s\n' "$(run_info "$dir" start)"
+  if [[ -n "${st[start_epoch]:-}" ]]; then
+    printf 'Elapsed:  %s\n' "$(fmt_duration $(( now - st[start_epoch] )))"
+  fi
+  if ! kill -0 "$(run_info "$dir" pid)" 2>/dev/null; then
+    printf 'State:    stale\n'
+  else
+    printf 'State:    %s%s\n' "${st[state]:-running}" "${st[attempt]:+ (attempt ${st[attempt]})}"
+  fi
+  if [[ -n "${st[progress]:-}" ]]; then
+    printf 'Progress: %s\n' "${st[progress]}"
+  fi
+  printf '\nRecent output:\n'
+  show_tail "$dir" "$TAIL_LINES" | sed 's/^/  /'
+}
+
+# Prints the last LINES lines of a run's output (ANSI codes stripped,
+# repeats collapsed, progress reports left out).
+show_tail() {
+  RUN_TMP="$(run_info "$1" tmp)"
+  if [[ -z "$RUN_TMP" || ! -d "$RUN_TMP" ]]; then
+    echo "No output kept for run $(basename "$1")" >&2
+    return 1
+  fi
+  TAIL_LINES="$2" output_tail
+}
+
+# Asks the wrapper to stop its command; the wrapper reports the
+# cancellation itself.
+kill_run() {
+  local pid
+  pid="$(run_info "$1" pid)"
+  if ! kill -0 "$pid" 2>/dev/null; then
+    echo "Run $(basename "$1") is not running" >&2
+    exit 1
+  fi
+  : >"${1}/cancel"
+  kill -TERM "$pid"
+}
+
+# Epoch when a silence of DURATION ends, or 0 without one.
+silence_until() {
+  if [[ -z "${1:-}" ]]; then
+    echo 0
+    return
+  fi
+  local secs
+  secs="$(parse_duration "$1")" || return 2
+  echo $(( $(date +%s) + secs ))
+}
+
+if [[ $# -eq 0 ]]; then
+  usage
+fi
+if [[ "$1" == list ]]; then
+  list_runs
+  exit 0
+fi
+case "$1" in
+  submit) shift; submit_run "$@"; exit 0 ;;
+  tmux-status) tmux_status; exit 0 ;;
+  waybar-json) waybar_json; exit 0 ;;
+  xbar) xbar_status; exit 0 ;;
+esac
+if [[ "$1" == forget && $# -eq 2 ]]; then
+  LABEL="$2"
+  rm -f "$(duration_file)"
+  exit 0
+fi
+if [[ $# -lt 2 ]]; then
+  usage
+fi
+
+dir="$(find_run "$1")"
+action="$2"
+shift 2
+case "$action" in
+  mute)
+    until="$(silence_until "${1:-}")" || exit 2
+    echo "${until} all" >>"${dir}/silence" ;;
+  snooze)
+    if [[ $# -lt 1 ]]; then
+      usage
+    fi
+    if ! event_filter_valid "$1"; then
+      echo "Unknown event or class in filter: $1" >&2
+      exit 2
+    fi
+    until="$(silence_until "${2:-}")" || exit 2
+    echo "${until} $1" >>"${dir}/silence" ;;
+  unmute)
+    rm -f "${dir}/silence" ;;
+  note)
+    post=keep
+    if [[ "${1:-}" == --post ]]; then
+      post=post
+      shift
+    fi
+    if [[ $# -lt 1 ]]; then
+      usage
+    fi
+    printf '%(%s)T %s %s\n' -1 "$post" "${*//$'\n'/ }" >>"${dir}/notes" ;;
+  status)
+    show_status "$dir" ;;
+  tail)
+    if ! [[ "${1:-20}" =~ ^[1-9][0-9]*$ ]]; then
+      usage
+    fi
+    show_tail "$dir" "${1:-20}" ;;
+  kill)
+    kill_run "$dir" ;;
+  approve|reject)
+    echo "$action" >"${dir}/approval" ;;
+  *)
+    usage ;;
+esac
@@ -0,0 +1,107 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Progress reports from inside a job run by oc-run-notify.sh or
+# oc-run-notify-ssh.sh, without writing the protocol JSON by hand.
+#
+# Usage:
+#   oc-notify-emit.sh progress <percent|current/total> [--msg <text>] [name=value ...]
+#   oc-notify-emit.sh metric <name=value> ...
+#   oc-notify-emit.sh phase <name>
+#   oc-notify-emit.sh artifact <path>
+#   oc-notify-emit.sh approve <name>
+#
+# Reports go to $OCNOTIFY_PROGRESS_FIFO while the wrapper is listening, and
+# to stdout as ::ocnotify:: lines otherwise, so the same script also works
+# unwrapped (the lines are then just printed).
+#
+# approve asks for approval before <name> (the wrapper sends a ⏸️ message)
+# and waits for the answer: it exits 0 once approved and 1 if rejected.
+# Unwrapped it approves right away.
+#
+# Example:
+#   for epoch in $(seq 1 8); do
+#     ...
+#     oc-notify-emit.sh progress "$epoch/8" --msg "epoch $epoch" loss="$loss"
+#   done
+
+usage() {
+  sed -n '7,12s/^# //p' "${BASH_SOURCE[0]}" >&2
+  exit 2
+}
+
+json_str() {
+  local s="$1"
+  s="${s//\\/\\\\}"
+  s="${s//\"/\\\"}"
+  s="${s//$'\n'/\\n}"
+  s="${s//$'\r'/\\r}"
+  s="${s//$'\t'/\\t}"
+  printf '"%s"' "$s"
+}
+
+# Adds "name": value members for name=value arguments to JSON.
+add_metrics() {
+  local arg
+  for arg in "$@"; do
+    if ! [[ "$arg" =~ ^([A-Za-z_][A-Za-z0-9_.-]*)=(-?[0-9.]+([eE][+-]?[0-9]+)?)$ ]]; then
+      echo "Invalid metric: ${arg} (expected name=number)" >&2
+      exit 2
+    fi
+    JSON+=",$(json_str "${BASH_REMATCH[1]}"):${BASH_REMATCH[2]}"
+  done
+}
+
+emit() {
+  local line="{${JSON#,}}"
+  if [[ -n "${OCNOTIFY_PROGRESS_FIFO:-}" && -p "$OCNOTIFY_PROGRESS_FIFO" ]]; then
+    printf '%s\n' "$line" >"$OCNOTIFY_PROGRESS_FIFO"
+  else
+    printf '::ocnotify::%s\n' "$line"
+  fi
+}
+
+if [[ $# -lt 2 ]]; then
+  usage
+fi
+kind="$1"
+shift
+JSON=""
+case "$kind" in
+  progress)
+    if [[ "$1" =~ ^([0-9]+([.][0-9]+)?)/([0-9]+([.][0-9]+)?)$ ]]; then
+      JSON+=",\"current\":${BASH_REMATCH[1]},\"total\":${BASH_REMATCH[3]}"
+    elif [[ "$1" =~ ^[0-9]+([.][0-9]+)?%?$ ]]; then
+      JSON+=",\"percent\":${1%\%}"
+    else
+      echo "Invalid progress: $1 (expected a percent or current/total)" >&2
+      exit 2
+    fi
+    shift
+    if [[ "${1:-}" == --msg ]]; then
+      JSON+=",\"summary\":$(json_str "${2:-}")"
+      shift 2 || shift
+    fi
+    add_metrics "$@" ;;
+  metric)
+    add_metrics "$@" ;;
+  phase)
+    JSON+=",\"phase\":$(json_str "$1")" ;;
+  artifact)
+    JSON+=",\"artifact\":$(json_str "$1")" ;;
+  approve)
+    if [[ -z "${OCNOTIFY_DECISION_FILE:-}" ]]; then
+      exit 0
+    fi
+    rm -f "$OCNOTIFY_DECISION_FILE"
+    JSON+=",\"approve\":$(json_str "$1")"
+    emit
+    until [[ -s "$OCNOTIFY_DECISION_FILE" ]]; do
+      sleep 1
+    done
+    [[ "$(<"$OCNOTIFY_DECISION_FILE")" == approve ]]
+    exit ;;
+  *)
+    usage ;;
+esac
+emit
@@ -0,0 +1,586 @@
+# Notification events, per-sink formatting and delivery for the
+# oc-run-notify wrappers. Sourced by oc-notify-common.sh.
+#
+# Callers describe an event once and every sink renders it in its own
+# format (chat markdown, Slack mrkdwn, plain text, HTML email, JSON):
+#
+#   event_begin failed "⚒️ train failed (exit=1)"
+#   event_field Start "$start_iso UTC"
+#   event_tail "$(output_tail)"
+#   notify [MEDIA]
+
+# Registers a NAME[:FILTER] sink spec.
+add_sink() {
+  local name="${1%%:*}" filter="all"
+  if [[ "$1" == *:* ]]; then
+    filter="${1#*:}"
+  elif [[ "$name" == "email" ]]; then
+    filter="final"
+  fi
+  case "$name" in
+    openclaw|desktop|ntfy|webhook|email) ;;
+    *) echo "Unknown sink: $name" >&2; exit 2 ;;
+  esac
+  SINK_NAMES+=("$name")
+  SINK_FILTERS+=("$filter")
+}
+
+has_sink() {
+  local name
+  for name in "${SINK_NAMES[@]}"; do
+    if [[ "$name" == "$1" ]]; then
+      return 0
+    fi
+  done
+  return 1
+}
+
+# Severity of an event: critical, warning or info.
+event_severity() {
+  case "$1" in
+    failed|interrupted|timeout|preempt|alert|job|crashloop) echo critical ;;
+    budget|stall|threshold|battery|overdue|retry|approval|levels|probe|regression) echo warning ;;
+    *) echo info ;;
+  esac
+}
+
+# Whether EVENT passes a sink FILTER (see --sink).
+event_matches() {
+  local filter="$1" event="$2" term severity
+  severity="$(event_severity "$event")"
+  for term in ${filter//+/ }; do
+    case "$term" in
+      all) return 0 ;;
+      final) [[ "$event" =~ ^(completed|failed|interrupted|timeout)$ ]] && return 0 ;;
+      failure) [[ "$event" =~ ^(failed|interrupted|timeout)$ ]] && return 0 ;;
+      critical) [[ "$severity" == critical ]] && return 0 ;;
+      warning) [[ "$severity" == critical || "$severity" == warning ]] && return 0 ;;
+      milestone|milestones) [[ "$event" == progress ]] && return 0 ;;
+      *) [[ "$term" == "$event" ]] && return 0 ;;
+    esac
+  done
+  return 1
+}
+
+# Whether $1 is the name of an event the wrappers send.
+event_known() {
+  case "$1" in
+    completed|failed|interrupted|timeout|started|progress|heartbeat) ;;
+    alert|approval|approved|battery|best|budget|crashloop|job|levels) ;;
+    note|overdue|power|preempt|probe|ready|recovered|rejected|resumed) ;;
+    regression|retry|stall|threshold) ;;
+    *) return 1 ;;
+  esac
+}
+
+# Whether every term of FILTER is an event class or a known event name.
+event_filter_valid() {
+  local term
+  for term in ${1//+/ }; do
+    case "$term" in
+      all|final|failure|critical|warning|milestone|milestones) ;;
+      *) event_known "$term" || return 1 ;;
+    esac
+  done
+  [[ -n "$1" ]]
+}
+
+# Starts a new event: machine name (e.g. "failed") and one-line title.
+event_begin() {
+  EV_NAME="$1"
+  EV_TITLE="$2"
+  EV_BODY=""
+  EV_KEYS=()
+  EV_VALUES=()
+  EV_TAIL=""
+}
+
+event_field() {
+  EV_KEYS+=("$1")
+  EV_VALUES+=("$2")
+}
+
+# Output lines shown under the fields; empty text is ignored.
+event_tail() {
+  EV_TAIL="$1"
+}
+
+# Template for EVENT: --template-<event> first, then the
+# OCNOTIFY_TEMPLATE_<EVENT> variable (DONE is accepted for completed,
+# MILESTONE for progress).
+event_template() {
+  local event="$1" var
+  if [[ -n "${TEMPLATES[$event]:-}" ]]; then
+    printf '%s' "${TEMPLATES[$event]}"
+    return
+  fi
+  var="OCNOTIFY_TEMPLATE_${event^^}"
+  if [[ "$event" == "completed" && -z "${!var:-}" ]]; then
+    var="OCNOTIFY_TEMPLATE_DONE"
+  elif [[ "$event" == "progress" && -z "${!var:-}" ]]; then
+    var="OCNOTIFY_TEMPLATE_MILESTONE"
+  fi
+  printf '%s' "${!var:-}"
+}
+
+# Latest value of metric NAME with an optional format: a printf conversion
+# without the "%" (".3f", "d", ".2e") or ".1%" for a percentage. Unknown
+# metrics print "?".
+format_metric() {
+  local value fmt="$2"
+  if ! value="$(metric_value "$1")"; then
+    printf '?'
+    return
+  fi
+  if [[ -z "$fmt" ]] || ! [[ "$value" =~ ^-?[0-9.]+([eE][+-]?[0-9]+)?$ ]]; then
+    printf '%s' "$value"
+  elif [[ "$fmt" =~ ^(\.[0-9]+)?%$ ]]; then
+    awk -v v="$value" -v f="%${BASH_REMATCH[1]:-.0}f%%" 'BEGIN { printf f, v * 100 }'
+  elif [[ "$fmt" =~ ^[0-9]*(\.[0-9]+)?[dfeg]$ ]]; then
+    awk -v v="$value" -v f="%${fmt}" 'BEGIN { printf f, v }'
+  else
+    printf '%s' "$value"
+  fi
+}
+
+# If the current event has a template, renders it into the title (first
+# line) and body (the rest), replacing the default title and fields.
+# Placeholders: {label} {host} {event} {exit} {elapsed} {start} {end} {log}
+# {cost} {percent} {progress} {current} {total} {eta}, {metric.NAME} or
+# {metric.NAME:FMT} (see format_metric) and {delta.NAME}; "\n" in a template
+# is a line break.
+apply_template() {
+  local text
+  text="$(event_template "$EV_NAME")"
+  if [[ -z "$text" ]]; then
+    return
+  fi
+  local now elapsed
+  now="$(date +%s)"
+  elapsed=$(( ${end_epoch:-$now} - start_epoch ))
+  local -A vars=(
+    [label]="$LABEL"
+    [host]="${HOST:-$(hostname)}"
+    [event]="$EV_NAME"
+    [exit]="${RC:-}"
+    [elapsed]="$(fmt_duration "$elapsed")"
+    [start]="${start_iso:-}"
+    [end]="${end_iso:-}"
+    [log]="$LOG_PATH"
+    [cost]=""
+    [percent]="${PROGRESS_PERCENT:-}"
+    [progress]="$(progress_text || true)"
+    [current]="${PROGRESS_CURRENT:-}"
+    [total]="${PROGRESS_TOTAL:-}"
+    [eta]="$(progress_eta || true)"
+  )
+  if [[ -n "$COST_RATE" ]]; then
+    vars[cost]="\$$(cost_so_far "$elapsed")"
+  fi
+  local key
+  for key in "${!vars[@]}"; do
+    text="${text//"{${key}}"/"${vars[$key]}"}"
+  done
+  local whole value
+  while [[ "$text" =~ \{metric\.([A-Za-z0-9_.-]+)(:([^}]*))?\} ]]; do
+    whole="${BASH_REMATCH[0]}"
+    value="$(format_metric "${BASH_REMATCH[1]}" "${BASH_REMATCH[3]}")"
+    text="${text//"$whole"/"$value"}"
+  done
+  while [[ "$text" =~ \{delta\.([A-Za-z0-9_.-]+)\} ]]; do
+    whole="${BASH_REMATCH[0]}"
+    value="$(metric_trend "${BASH_REMATCH[1]}")"
+    text="${text//"$whole"/"$value"}"
+  done
+  text="${text//\\n/$'\n'}"
+  EV_TITLE="${text%%$'\n'*}"
+  EV_BODY=""
+  if [[ "$text" == *$'\n'* ]]; then
+    EV_BODY="${text#*$'\n'}"
+  fi
+  EV_KEYS=()
+  EV_VALUES=()
+}
+
+# Quotes $1 as a JSON string.
+json_str() {
+  local s="$1"
+  s="${s//\\/\\\\}"
+  s="${s//\"/\\\"}"
+  s="${s//$'\n'/\\n}"
+  s="${s//$'\r'/\\r}"
+  s="${s//$'\t'/\\t}"
+  printf '"%s"' "$(printf '%s' "$s" | tr -d '\000-\010\013\014\016-\037')"
+}
+
+# Prints $1 unquoted if it is a JSON number, otherwise as a JSON string.
+json_number() {
+  if [[ "$1" =~ ^-?(0|[1-9][0-9]*)([.][0-9]+)?([eE][+-]?[0-9]+)?$ ]]; then
+    printf '%s' "$1"
+  else
+    json_str "$1"
+  fi
+}
+
+html_escape() {
+  printf '%s' "$1" | sed -e 's/&/\&amp;/g' -e 's/</\&lt;/g' -e 's/>/\&gt;/g'
+}
+
+# Renders the current event. FORMAT is markdown (chat), slack (mrkdwn),
+# plain or html. With "body" as second arg the title is left out, for sinks
+# that carry the title separately.
+render_event() {
+  local format="$1" part="${2:-full}" i
+  case "$format" in
+    markdown|plain)
+      if [[ "$part" == full ]]; then
+        printf '%s\n' "$EV_TITLE"
+      fi
+      if [[ -n "$EV_BODY" ]]; then
+        printf '%s\n' "$EV_BODY"
+      fi
+      for i in "${!EV_KEYS[@]}"; do
+        printf '%s: %s\n' "${EV_KEYS[$i]}" "${EV_VALUES[$i]}"
+      done
+      if [[ -n "$EV_TAIL" && "$format" == markdown ]]; then
+        printf 'Last lines:\n```\n%s\n```\n' "$EV_TAIL"
+      elif [[ -n "$EV_TAIL" ]]; then
+        printf '\nLast lines:\n%s\n' "$EV_TAIL"
+      fi ;;
+    slack)
+      if [[ "$part" == full ]]; then
+        printf '*%s*\n' "$EV_TITLE"
+      fi
+      if [[ -n "$EV_BODY" ]]; then
+        printf '%s\n' "$EV_BODY"
+      fi
+      for i in "${!EV_KEYS[@]}"; do
+        printf '*%s:* %s\n' "${EV_KEYS[$i]}" "${EV_VALUES[$i]}"
+      done
+      if [[ -n "$EV_TAIL" ]]; then
+        printf '```\n%s\n```\n' "$EV_TAIL"
+      fi ;;
+    html)
+      if [[ "$part" == full ]]; then
+        printf '<p><b>%s</b></p>\n' "$(html_escape "$EV_TITLE")"
+      fi
+      if [[ -n "$EV_BODY" ]]; then
+        printf '<p>%s</p>\n' "$(html_escape "$EV_BODY" | sed 's|$|<br>|')"
+      fi
+      if (( ${#EV_KEYS[@]} > 0 )); then
+        printf '<table>\n'
+        for i in "${!EV_KEYS[@]}"; do
+          printf '<tr><th align="left">%s</th><td>%s</td></tr>\n' \
+            "$(html_escape "${EV_KEYS[$i]}")" "$(html_escape "${EV_VALUES[$i]}")"
+        done
+        printf '</table>\n'
+      fi
+      if [[ -n "$EV_TAIL" ]]; then
+        printf '<p>Last lines:</p>\n<pre>%s</pre>\n' "$(html_escape "$EV_TAIL")"
+      fi ;;
+  esac
+}
+
+# JSON payload describing the current event for the webhook sink: "summary"
+# is the latest progress summary, "text" the whole message as plain text.
+event_json() {
+  local elapsed=$(( $(date +%s) - start_epoch ))
+  local exit_status="null" log="null" fields="" i percent="null" current="null" total="null" summary="null"
+  if [[ -n "${RC:-}" ]]; then
+    exit_status="$RC"
+  fi
+  if [[ -n "$LOG_PATH" ]]; then
+    log="$(json_str "$LOG_PATH")"
+  fi
+  if [[ -n "${PROGRESS_PERCENT:-}" ]]; then
+    percent="$(json_number "$PROGRESS_PERCENT")"
+  fi
+  if [[ -n "${PROGRESS_CURRENT:-}" && -n "${PROGRESS_TOTAL:-}" ]]; then
+    current="$(json_number "$PROGRESS_CURRENT")"
+    total="$(json_number "$PROGRESS_TOTAL")"
+  fi
+  if [[ -n "${PROGRESS_SUMMARY:-}" ]]; then
+    summary="$(json_str "$PROGRESS_SUMMARY")"
+  fi
+  for i in "${!EV_KEYS[@]}"; do
+    fields+="${fields:+,}$(json_str "${EV_KEYS[$i]}"):$(json_str "${EV_VALUES[$i]}")"
+  done
+  printf '{"event":%s,"severity":%s,"label":%s,"host":%s,"title":%s,"fields":{%s},"percent":%s,"current":%s,"total":%s,"summary":%s,"text":%s,"elapsed_sec":%d,"exit_status":%s,"log":%s,"tail":%s,"time":%s}' \
+    "$(json_str "$EV_NAME")" "$(json_str "$(event_severity "$EV_NAME")")" \
+    "$(json_str "$LABEL")" "$(json_str "${HOST:-$(hostname)}")" "$(json_str "$EV_TITLE")" \
+    "$fields" "$percent" "$current" "$total" "$summary" "$(json_str "$(render_event plain)")" "$elapsed" "$exit_status" "$log" \
+    "$(json_str "${EV_TAIL:-$(output_tail)}")" "$(json_str "$(now_iso)")"
+}
+
+# RFC 2047 encoded header value, so emoji survive in the subject.
+mime_header() {
+  printf '=?UTF-8?B?%s?=' "$(printf '%s' "$1" | base64 | tr -d '\n')"
+}
+
+# Format used for the openclaw sink: --openclaw-format, else mrkdwn for
+# Slack and markdown for everything else.
+openclaw_format() {
+  if [[ -n "$OPENCLAW_FORMAT" ]]; then
+    echo "$OPENCLAW_FORMAT"
+  elif [[ "$CHANNEL" == "slack" ]]; then
+    echo slack
+  else
+    echo markdown
+  fi
+}
+
+# Whether this openclaw's "message send" accepts FLAG (checked once).
+openclaw_supports() {
+  if [[ -z "${OPENCLAW_SEND_HELP+x}" ]]; then
+    OPENCLAW_SEND_HELP="$(openclaw message send --help 2>&1 || true)"
+  fi
+  [[ "$OPENCLAW_SEND_HELP" == *"$1"* ]]
+}
+
+# Level (high, normal, low, silent or empty) for the current event in a
+# map of severity=level and event=level entries; an entry for the event
+# name wins over one for its severity.
+priority_level() {
+  local severity entry level="" named=""
+  severity="$(event_severity "$EV_NAME")"
+  for entry in ${1//,/ }; do
+    if [[ "${entry%%=*}" == "$EV_NAME" ]]; then
+      named="${entry#*=}"
+    elif [[ "${entry%%=*}" == "$severity" ]]; then
+      level="${entry#*=}"
+    fi
+  done
+  printf '%s' "${named:-$level}"
+}
+
+# Sets OPENCLAW_PRIORITY_ARGS for the current event from the priority map
+# in --openclaw-priority, OCNOTIFY_OPENCLAW_PRIORITY_<CHANNEL> or
+# OCNOTIFY_OPENCLAW_PRIORITY (default critical=high,progress=silent,heartbeat=silent). Flags
+# the installed openclaw does not know are left out.
+openclaw_priority_args() {
+  OPENCLAW_PRIORITY_ARGS=()
+  local var="OCNOTIFY_OPENCLAW_PRIORITY_${CHANNEL^^}"
+  var="${var//[^A-Z0-9_]/_}"
+  local level
+  level="$(priority_level "${OPENCLAW_PRIORITY:-${!var:-${OCNOTIFY_OPENCLAW_PRIORITY:-critical=high,progress=silent,heartbeat=silent}}}")"
+  case "$level" in
+    silent)
+      if openclaw_supports --silent; then
+        OPENCLAW_PRIORITY_ARGS=(--silent)
+      fi ;;
+    high|normal|low)
+      if openclaw_supports --priority; then
+        OPENCLAW_PRIORITY_ARGS=(--priority "$level")
+      fi ;;
+  esac
+}
+
+send_openclaw() {
+  local media="${1:-}"
+  local media_args=()
+  if [[ -n "$media" ]]; then
+    media_args=(--media "$media")
+  fi
+  openclaw_priority_args
+  openclaw message send --channel "$CHANNEL" --target "$TARGET" \
+    --message "$(render_event "$(openclaw_format)")" "${media_args[@]}" "${OPENCLAW_PRIORITY_ARGS[@]}"
+}
+
+# Native desktop notification.
+send_desktop() {
+  local body
+  body="$(render_event plain body)"
+  if command -v notify-send >/dev/null 2>&1; then
+    local urgency="normal"
+    if [[ "$(event_severity "$EV_NAME")" == "critical" ]]; then
+      urgency="critical"
+    fi
+    notify-send --app-name=ocnotify --urgency="$urgency" "$EV_TITLE" "$body"
+  elif command -v osascript >/dev/null 2>&1; then
+    osascript -e 'on run argv' \
+      -e 'display notification (item 2 of argv) with title (item 1 of argv)' \
+      -e 'end run' "$EV_TITLE" "$body"
+  fi
+}
+
+# Resolves the credential setting named $1 at send time. $1_CMD, if set, is
+# run and its output used (e.g. OCNOTIFY_SMTP_PASS_CMD="pass show smtp").
+# Otherwise "keyring:NAME" is looked up in the system keyring (secret-tool
+# on Linux, the login keychain on macOS) under service "ocnotify",
+# "age:FILE" is decrypted with the identity in OCNOTIFY_AGE_IDENTITY
+# (default ~/.config/ocnotify/age.key), and anything else is used as-is.
+secret_value() {
+  local cmd="${1}_CMD"
+  if [[ -n "${!cmd:-}" ]]; then
+    bash -c "${!cmd}"
+    return
+  fi
+  local value="${!1:-}"
+  case "$value" in
+    keyring:*)
+      if command -v secret-tool >/dev/null 2>&1; then
+        secret-tool lookup service ocnotify account "${value#keyring:}"
+      else
+        security find-generic-password -s ocnotify -a "${value#keyring:}" -w
+      fi ;;
+    age:*)
+      age -d -i "${OCNOTIFY_AGE_IDENTITY:-${XDG_CONFIG_HOME:-$HOME/.config}/ocnotify/age.key}" "${value#age:}" ;;
+    *)
+      printf '%s' "$value" ;;
+  esac
+}
+
+# Publishes to ntfy at the priority from --ntfy-priority or
+# OCNOTIFY_NTFY_PRIORITY (default critical=high,progress=silent,heartbeat=silent). MEDIA is
+# uploaded as the attachment, with the text in the Message header.
+send_ntfy() {
+  local media="${1:-}"
+  local url="$OCNOTIFY_NTFY_TOPIC"
+  if [[ "$url" != http://* && "$url" != https://* ]]; then
+    url="${OCNOTIFY_NTFY_SERVER:-https://ntfy.sh}/${url}"
+  fi
+  local priority
+  case "$(priority_level "${NTFY_PRIORITY:-${OCNOTIFY_NTFY_PRIORITY:-critical=high,progress=silent,heartbeat=silent}}")" in
+    high) priority="high" ;;
+    low) priority="low" ;;
+    silent) priority="min" ;;
+    *) priority="default" ;;
+  esac
+  local body
+  body="$(render_event plain body)"
+  body="${body:-$EV_TITLE}"
+  local args=(-fsS -m 30 -H "Title: $(mime_header "$EV_TITLE")" -H "Priority: ${priority}" -H "Tags: ${EV_NAME}")
+  if [[ -n "${OCNOTIFY_NTFY_TOKEN:-}${OCNOTIFY_NTFY_TOKEN_CMD:-}" ]]; then
+    args+=(-H "Authorization: Bearer $(secret_value OCNOTIFY_NTFY_TOKEN)")
+  fi
+  if [[ -n "$media" ]]; then
+    args+=(-T "$media" -H "Filename: $(basename "$media")" -H "Message: $(mime_header "$body")")
+  else
+    args+=(--data-binary "$body")
+  fi
+  curl "${args[@]}" "$url"
+}
+
+send_webhook() {
+  event_json \
+    | curl -fsS -m 10 -X POST -H 'Content-Type: application/json' --data-binary @- "$(secret_value WEBHOOK_URL)"
+}
+
+# Sends the event by email as plain text plus HTML, with the output tail
+# (the event's own, or the last TAIL_LINES lines) and MEDIA attached.
+send_email() {
+  local media="${1:-}"
+  local from="${OCNOTIFY_EMAIL_FROM:-ocnotify@$(hostname)}"
+  local boundary="ocnotify-${RANDOM}${RANDOM}${RANDOM}"
+  local mail="${RUN_TMP}/mail.eml"
+  local saved_tail="$EV_TAIL"
+  if [[ -z "$EV_TAIL" ]]; then
+    EV_TAIL="$(output_tail)"
+  fi
+  {
+    printf 'From: %s\nTo: %s\nSubject: %s\n' \
+      "$from" "$OCNOTIFY_EMAIL_TO" "$(mime_header "$EV_TITLE")"
+    printf 'MIME-Version: 1.0\nContent-Type: multipart/mixed; boundary="%s"\n\n' "$boundary"
+    printf -- '--%s\nContent-Type: multipart/alternative; boundary="%s-alt"\n\n' "$boundary" "$boundary"
+    printf -- '--%s-alt\nContent-Type: text/plain; charset=utf-8\n\n' "$boundary"
+    render_event plain
+    printf -- '\n--%s-alt\nContent-Type: text/html; charset=utf-8\n\n' "$boundary"
+    render_event html
+    printf -- '\n--%s-alt--\n' "$boundary"
+    if [[ -n "$media" ]]; then
+      local name type="application/octet-stream"
+      name="$(basename "$media")"
+      case "$name" in
+        *.png) type="image/png" ;;
+        *.jpg|*.jpeg) type="image/jpeg" ;;
+        *.svg) type="image/svg+xml" ;;
+      esac
+      printf -- '\n--%s\nContent-Type: %s; name="%s"\n' "$boundary" "$type" "$name"
+      printf 'Content-Transfer-Encoding: base64\nContent-Disposition: attachment; filename="%s"\n\n' "$name"
+      base64 <"$media"
+    fi
+    printf -- '\n--%s--\n' "$boundary"
+  } >"$mail"
+  EV_TAIL="$saved_tail"
+
+  if [[ -n "${OCNOTIFY_SMTP_URL:-}" ]]; then
+    local args=(-fsS -m 60 --ssl --crlf --url "$OCNOTIFY_SMTP_URL" --mail-from "$from" --upload-file "$mail")
+    local rcpt
+    for rcpt in ${OCNOTIFY_EMAIL_TO//,/ }; do
+      args+=(--mail-rcpt "$rcpt")
+    done
+    if [[ -n "${OCNOTIFY_SMTP_USER:-}" ]]; then
+      args+=(--user "${OCNOTIFY_SMTP_USER}:$(secret_value OCNOTIFY_SMTP_PASS)")
+    fi
+    curl "${args[@]}"
+  else
+    sendmail -t <"$mail"
+  fi
+}
+
+# Appends what SINK sent for the current event to the --audit-log:
+# destination, whether delivery succeeded, the text as that sink renders it
+# and the attachment's name and size. Webhook URLs are cut to scheme and
+# host, since the path often carries a token.
+audit_record() {
+  local sink="$1" delivered="$2" media="${3:-}" dest payload attachment="null"
+  case "$sink" in
+    openclaw)
+      dest="${CHANNEL}:${TARGET}"
+      payload="$(render_event "$(openclaw_format)")" ;;
+    ntfy)
+      dest="$OCNOTIFY_NTFY_TOPIC"
+      if [[ "$dest" != http://* && "$dest" != https://* ]]; then
+        dest="${OCNOTIFY_NTFY_SERVER:-https://ntfy.sh}/${dest}"
+      fi
+      payload="$(render_event plain)" ;;
+    webhook)
+      dest="$(sed -E 's|^([a-z]+://[^/]*).*|\1/...|' <<<"$WEBHOOK_URL")"
+      payload="$(event_json)" ;;
+    email)
+      dest="$OCNOTIFY_EMAIL_TO"
+      payload="$(EV_TAIL="${EV_TAIL:-$(output_tail)}"; render_event plain)" ;;
+  esac
+  if [[ -n "$media" && -f "$media" ]]; then
+    attachment="{\"name\":$(json_str "$(basename "$media")"),\"bytes\":$(wc -c <"$media")}"
+  fi
+  mkdir -p "$(dirname "$AUDIT_LOG")"
+  (umask 077
+   printf '{"time":%s,"run":%s,"label":%s,"event":%s,"sink":%s,"destination":%s,"delivered":%s,"payload":%s,"attachment":%s}\n' \
+     "$(json_str "$(now_iso)")" "$(json_str "$RUN_ID")" "$(json_str "$LABEL")" "$(json_str "$EV_NAME")" \
+     "$(json_str "$sink")" "$(json_str "$dest")" "$delivered" "$(json_str "$payload")" "$attachment" \
+     >>"$AUDIT_LOG")
+}
+
+# Best-effort notify: broadcasts the current event to every sink whose
+# filter accepts it; never fails the wrapper. MEDIA is a local file
+# attached where the sink supports it.
+notify() {
+  local media="${1:-}"
+  local i delivered
+  if event_silenced "$EV_NAME"; then
+    return 0
+  fi
+  # --notify on-done / on-failure: a filter every sink has to pass first.
+  if [[ -n "${NOTIFY_FILTER:-}" ]] && ! event_matches "$NOTIFY_FILTER" "$EV_NAME"; then
+    return 0
+  fi
+  apply_template
+  for i in "${!SINK_NAMES[@]}"; do
+    if ! event_matches "${SINK_FILTERS[$i]}" "$EV_NAME"; then
+      continue
+    fi
+    delivered=true
+    case "${SINK_NAMES[$i]}" in
+      openclaw) send_openclaw "$media" ;;
+      desktop) send_desktop ;;
+      ntfy) send_ntfy "$media" ;;
+      webhook) send_webhook ;;
+      email) send_email "$media" ;;
+    esac >/dev/null 2>&1 || delivered=false
+    # Desktop notifications stay on this machine.
+    if [[ -n "$AUDIT_LOG" && "${SINK_NAMES[$i]}" != desktop ]]; then
+      audit_record "${SINK_NAMES[$i]}" "$delivered" "$media" || true
+    fi
+  done
+}
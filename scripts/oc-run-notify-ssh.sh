@@ -3,6 +3,7 @@ set -euo pipefail
 
 # Event-driven remote wrapper.
 # Runs a remote command via SSH, then sends OpenClaw message on completion/failure.
+# See oc-notify-common.sh for the options shared with oc-run-notify.sh.
 #
 # Example:
 #   oc-run-notify-ssh.sh \
@@ -12,55 +13,97 @@ set -euo pipefail
 #     --target 366115325797990400 \
 #     -- ssh-cmd 'cd ~/neural-mutator && source .venv/bin/activate && python -m src.train ...'
 
+source "$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)/oc-notify-common.sh"
+
 HOST=""
 LABEL="remote-job"
-CHANNEL=""
-TARGET=""
 
 while [[ $# -gt 0 ]]; do
   case "$1" in
     --host)
       HOST="${2:-}"; shift 2 ;;
-    --label)
-      LABEL="${2:-}"; shift 2 ;;
-    --channel)
-      CHANNEL="${2:-}"; shift 2 ;;
-    --target)
-      TARGET="${2:-}"; shift 2 ;;
     --)
       shift; break ;;
     *)
-      echo "Unknown arg: $1" >&2; exit 2 ;;
+      if ! parse_common_opt "$@"; then
+        echo "Unknown arg: $1" >&2; exit 2
+      fi
+      shift "$OPT_SHIFT" ;;
   esac
 done
 
-if [[ -z "$HOST" || -z "$CHANNEL" || -z "$TARGET" ]]; then
-  echo "--host, --channel, --target are required" >&2
+if [[ -z "$HOST" ]]; then
+  echo "--host is required" >&2
   exit 2
 fi
-if [[ $# -eq 0 ]]; then
+require_common_opts
+if (( ${#STEPS[@]} > 0 || ${#JOBS[@]} > 0 )) || [[ -n "$WATCH_FILE" ]]; then
+  if [[ $# -gt 0 ]]; then
+    echo "--step/--job/--watch-file and a remote command after -- are mutually exclusive" >&2
+    exit 2
+  fi
+  mapfile -d '' -t batch < <(batch_cmd)
+  set -- "$(printf '%q ' "${batch[@]}")"
+elif [[ $# -eq 0 ]]; then
   echo "Remote command required after --" >&2
   exit 2
 fi
 
 remote_cmd="$*"
-start_iso="$(date -u +%Y-%m-%dT%H:%M:%SZ)"
+HOST_SUFFIX=" on ${HOST}"
+# Every ssh call goes over one shared connection: probes, heartbeats and
+# host context checks run every few seconds for as long as the job does.
+SSH_CONTROL_DIR="$(mktemp -d "${TMPDIR:-/tmp}/ocnotify-ssh.XXXXXX")"
+SSH_MUX=(-o ControlMaster=auto -o "ControlPath=${SSH_CONTROL_DIR}/%C" -o ControlPersist=60)
+# The run's files on the job host live in a private (mode 700) directory
+# made for it, so other users there cannot plant an approval decision or
+# point the PID file elsewhere. The remote shell records its PID before
+# exec'ing the command, so signals and timeouts reach the remote process
+# group (sshd makes the shell a session leader) rather than the local ssh
+# client.
+REMOTE_DIR="$(ssh "${SSH_MUX[@]}" -n "$HOST" 'mktemp -d "${TMPDIR:-/tmp}/ocnotify.XXXXXX"')" || true
+if [[ ! "$REMOTE_DIR" =~ ^/[A-Za-z0-9._/-]+$ ]]; then
+  echo "Could not create a temporary directory on ${HOST}" >&2
+  rm -rf "$SSH_CONTROL_DIR"
+  exit 255
+fi
+REMOTE_PIDFILE="${REMOTE_DIR}/pid"
+REMOTE_FIFO="${REMOTE_DIR}/progress.fifo"
+DECISION_FILE="${REMOTE_DIR}/decision"
+REMOTE_CHILD=1
 
-set +e
-ssh "$HOST" "bash -lc $(printf '%q' "$remote_cmd")"
-rc=$?
-set -e
+host_sh() {
+  ssh "${SSH_MUX[@]}" -n "$HOST" "bash -lc $(printf '%q' "$1")"
+}
 
-end_iso="$(date -u +%Y-%m-%dT%H:%M:%SZ)"
-status="completed"
-if [[ "$rc" -ne 0 ]]; then
-  status="failed"
-fi
+host_fetch() {
+  local dest="${RUN_TMP}/$(basename "$1")"
+  scp -q "${SSH_MUX[@]}" "${HOST}:$1" "$dest" && printf '%s' "$dest"
+}
 
-msg="⚒️ ${LABEL} on ${HOST} ${status} (exit=${rc})\nStart: ${start_iso} UTC\nEnd: ${end_iso} UTC"
+cleanup_host() {
+  ssh "${SSH_MUX[@]}" -n "$HOST" "rm -rf $(printf '%q' "$REMOTE_DIR")" >/dev/null 2>&1 || true
+  ssh "${SSH_MUX[@]}" -O exit "$HOST" >/dev/null 2>&1 || true
+  rm -rf "$SSH_CONTROL_DIR"
+}
 
-set +e
-openclaw message send --channel "$CHANNEL" --target "$TARGET" --message "$msg" >/dev/null 2>&1
-set -e
+# Only a plain PID is signalled: "kill -- -0" or junk would hit other
+# processes.
+stop_child() {
+  local script="pid=\"\$(cat $(printf '%q' "$REMOTE_PIDFILE"))\"
+[[ \"\$pid\" =~ ^[1-9][0-9]*\$ ]] && kill -s $1 -- \"-\$pid\""
+  ssh "${SSH_MUX[@]}" -n "$HOST" "bash -c $(printf '%q' "$script")" 2>/dev/null
+}
+
+launch="echo \$\$ >$REMOTE_PIDFILE
+mkfifo -m 600 $REMOTE_FIFO && (bash -c $(printf '%q' "$PROGRESS_FIFO_READER") ocnotify-progress $REMOTE_FIFO \$\$ &)
+export OCNOTIFY_RUN_ID=$(printf '%q' "$RUN_ID") OCNOTIFY_LABEL=$(printf '%q' "$LABEL") OCNOTIFY_PROGRESS_FIFO=$REMOTE_FIFO OCNOTIFY_DECISION_FILE=$DECISION_FILE
+exec bash -lc $(printf '%q' "$remote_cmd")"
+ssh_opts=("${SSH_MUX[@]}")
+if [[ -n "$PTY" ]]; then
+  ssh_opts+=(-tt)
+fi
+run_monitored ssh "${ssh_opts[@]}" "$HOST" "bash -c $(printf '%q' "$launch")"
+notify_done
 
-exit "$rc"
+exit "$RC"
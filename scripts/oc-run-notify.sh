@@ -3,6 +3,7 @@ set -euo pipefail
 
 # Event-driven run wrapper for OpenClaw.
 # Runs a command, then sends a completion/failure message exactly once on exit.
+# See oc-notify-common.sh for the options shared with oc-run-notify-ssh.sh.
 #
 # Example:
 #   oc-run-notify.sh \
@@ -10,67 +11,97 @@ set -euo pipefail
 #     --channel discord \
 #     --target 366115325797990400 \
 #     --log /tmp/snake.log \
+#     --cost-rate 3.50/h --max-cost 80 \
 #     -- bash -lc 'source .venv/bin/activate && python -m src.train ...'
+#
+# Shell command strings can be passed with --sh instead of a command after
+# --. They run under bash with pipefail, the string is included in the final
+# message, and on failure the message names the failing segment, e.g.:
+#   oc-run-notify.sh --label build --channel discord --target ... \
+#     --sh 'make -j8 && ./run-tests | tee test.log'
+#
+# On Linux with systemd and cgroup v2, --cgroup runs the command in its own
+# transient scope so the final message reports exact CPU time, peak memory
+# and IO for the whole process tree. --memory-max <size> (e.g. 16G, implies
+# --cgroup) also enforces a hard memory limit on it.
+#
+# When the launch itself can't be changed but its output can be piped,
+# --stdin monitors the wrapper's input instead of running a command: lines
+# are printed and parsed like a command's output (milestones, alerts, ...),
+# and end of input counts as completion (the upstream exit status is
+# not visible; pipe stderr too with 2>&1 if progress bars go there):
+#   python train.py 2>&1 | oc-run-notify.sh --stdin --label MNIST --channel ...
+
+source "$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)/oc-notify-common.sh"
 
 LABEL="job"
-CHANNEL=""
-TARGET=""
-LOG_PATH=""
+HOST_SUFFIX=""
 
 while [[ $# -gt 0 ]]; do
   case "$1" in
-    --label)
-      LABEL="${2:-}"; shift 2 ;;
-    --channel)
-      CHANNEL="${2:-}"; shift 2 ;;
-    --target)
-      TARGET="${2:-}"; shift 2 ;;
-    --log)
-      LOG_PATH="${2:-}"; shift 2 ;;
+    --sh)
+      SH_CMD="${2:-}"; shift 2 ;;
+    --cgroup)
+      CGROUP=1; shift ;;
+    --memory-max)
+      MEMORY_MAX="${2:-}"; CGROUP=1; shift 2 ;;
+    --stdin)
+      PIPE_STDIN=1; shift ;;
     --)
       shift; break ;;
     *)
-      echo "Unknown arg: $1" >&2; exit 2 ;;
+      if ! parse_common_opt "$@"; then
+        echo "Unknown arg: $1" >&2; exit 2
+      fi
+      shift "$OPT_SHIFT" ;;
   esac
 done
 
-if [[ -z "$CHANNEL" || -z "$TARGET" ]]; then
-  echo "--channel and --target are required" >&2
-  exit 2
-fi
-if [[ $# -eq 0 ]]; then
+require_common_opts
+if (( ${#STEPS[@]} > 0 || ${#JOBS[@]} > 0 )) || [[ -n "$WATCH_FILE" ]]; then
+  if [[ $# -gt 0 || -n "$SH_CMD" ]]; then
+    echo "--step/--job/--watch-file, --sh and a command after -- are mutually exclusive" >&2
+    exit 2
+  fi
+  mapfile -d '' -t batch < <(batch_cmd)
+  set -- "${batch[@]}"
+elif [[ -n "$PIPE_STDIN" ]]; then
+  if [[ $# -gt 0 || -n "$SH_CMD" || -n "$PTY" ]]; then
+    echo "--stdin, --sh, --pty and a command after -- are mutually exclusive" >&2
+    exit 2
+  fi
+  # The child gets the wrapper's stdin, so it only has to copy it.
+  set -- cat
+elif [[ -n "$SH_CMD" ]]; then
+  if [[ $# -gt 0 ]]; then
+    echo "--sh and a command after -- are mutually exclusive" >&2
+    exit 2
+  fi
+  SH_STATUS="$(mktemp "${TMPDIR:-/tmp}/ocnotify-sh.XXXXXX")"
+  set -- env OCNOTIFY_SH_STATUS="$SH_STATUS" bash -c "${SH_PRELUDE}${SH_CMD}"
+elif [[ $# -eq 0 ]]; then
   echo "Command required after --" >&2
   exit 2
 fi
 
-start_iso="$(date -u +%Y-%m-%dT%H:%M:%SZ)"
-if [[ -n "$LOG_PATH" ]]; then
-  mkdir -p "$(dirname "$LOG_PATH")"
+if [[ -n "$PTY" ]]; then
+  if [[ "$(uname)" == Darwin ]]; then
+    set -- script -q /dev/null "$@"
+  else
+    set -- script -qefc "$(printf '%q ' "$@")" /dev/null
+  fi
 fi
-
-set +e
-if [[ -n "$LOG_PATH" ]]; then
-  "$@" >>"$LOG_PATH" 2>&1
-else
-  "$@"
-fi
-rc=$?
-set -e
-
-end_iso="$(date -u +%Y-%m-%dT%H:%M:%SZ)"
-status="completed"
-if [[ "$rc" -ne 0 ]]; then
-  status="failed"
-fi
-
-msg="⚒️ ${LABEL} ${status} (exit=${rc})\nStart: ${start_iso} UTC\nEnd: ${end_iso} UTC"
-if [[ -n "$LOG_PATH" ]]; then
-  msg+="\nLog: ${LOG_PATH}"
+if [[ -n "$CGROUP" ]]; then
+  if ! command -v systemd-run >/dev/null 2>&1 || [[ ! -f /sys/fs/cgroup/cgroup.controllers ]]; then
+    echo "--cgroup needs systemd-run and cgroup v2" >&2
+    exit 2
+  fi
+  CGROUP_STATS="$(mktemp "${TMPDIR:-/tmp}/ocnotify-cgroup.XXXXXX")"
+  mapfile -d '' -t prefix < <(cgroup_cmd)
+  set -- "${prefix[@]}" "$@"
 fi
 
-# Best-effort notify; do not mask underlying exit code.
-set +e
-openclaw message send --channel "$CHANNEL" --target "$TARGET" --message "$msg" >/dev/null 2>&1
-set -e
+run_monitored "$@"
+notify_done
 
-exit "$rc"
+exit "$RC"
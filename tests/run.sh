@@ -0,0 +1,307 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Replays the fixture corpus through the output parsers and smoke-tests the
+# wrapper's exit paths against a stub openclaw. No network or real OpenClaw
+# is needed.
+#
+#   tests/run.sh            Run every check; exits non-zero on a failure
+#   tests/run.sh --update   Rewrite the .expected files from current output
+#
+# fixtures/parsers/<profile>.log is fed through parser_stage with --parser
+# <profile>, and the progress reports it writes must match <profile>.expected.
+# fixtures/json_fields/<name>.json is checked the same way against
+# json_fields (with jq, python3 and the regex fallback), including
+# truncated, malformed and non-UTF-8 reports, and each
+# "input<TAB>expected" line of fixtures/normalize.tsv against
+# normalize_number. To cover a new parser, add a log of real output and run
+# with --update, then check the new .expected by hand.
+
+TESTS_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+FIXTURES="${TESTS_DIR}/fixtures"
+WRAPPER="${TESTS_DIR}/../scripts/oc-run-notify.sh"
+SSH_WRAPPER="${TESTS_DIR}/../scripts/oc-run-notify-ssh.sh"
+UPDATE=""
+if [[ "${1:-}" == "--update" ]]; then
+  UPDATE=1
+fi
+
+WORK="$(mktemp -d "${TMPDIR:-/tmp}/ocnotify-tests.XXXXXX")"
+trap 'rm -rf "$WORK"' EXIT
+export OCNOTIFY_CONFIG="${WORK}/no-config"
+export OCNOTIFY_RUNTIME_DIR="${WORK}/runtime"
+export XDG_STATE_HOME="${WORK}/state"
+# Login shells on the stub "remote" host read nothing from the real home.
+export HOME="${WORK}/home"
+mkdir -p "$HOME"
+# shellcheck source=../scripts/oc-notify-common.sh
+source "${TESTS_DIR}/../scripts/oc-notify-common.sh"
+set +e
+
+passed=0
+failed=0
+
+pass() {
+  passed=$(( passed + 1 ))
+}
+
+fail() {
+  failed=$(( failed + 1 ))
+  printf 'FAIL %s\n' "$1"
+  if [[ -n "${2:-}" ]]; then
+    printf '%s\n' "$2" | sed 's/^/  /'
+  fi
+}
+
+# Compares the output in file $2 with the expected file $3 for check $1, or
+# with --update stores it as the new expectation.
+check_output() {
+  if [[ -n "$UPDATE" ]]; then
+    cp "$2" "$3"
+    pass
+  elif [[ ! -f "$3" ]]; then
+    fail "$1" "missing $(basename "$3") (run with --update)"
+  elif diff -u "$3" "$2" >"${WORK}/diff"; then
+    pass
+  else
+    fail "$1" "$(cat "${WORK}/diff")"
+  fi
+}
+
+test_parsers() {
+  local log profile
+  for log in "${FIXTURES}"/parsers/*.log; do
+    profile="$(basename "$log" .log)"
+    RUN_TMP="${WORK}/${profile}"
+    mkdir -p "$RUN_TMP"
+    : >"${RUN_TMP}/progress"
+    PARSERS="$profile"
+    parser_stage <"$log" >/dev/null
+    check_output "parser ${profile}" "${RUN_TMP}/progress" "${log%.log}.expected"
+  done
+}
+
+# Each report goes through every JSON_DECODER installed here and through
+# the regex fallback, which is held to <name>.regex.expected where its
+# documented limits make it differ.
+test_json_fields() {
+  local json name decoder expected decoders=("")
+  command -v jq >/dev/null 2>&1 && decoders+=(jq)
+  command -v python3 >/dev/null 2>&1 && decoders+=(python3)
+  for json in "${FIXTURES}"/json_fields/*.json; do
+    name="$(basename "$json" .json)"
+    for decoder in "${decoders[@]}"; do
+      expected="${json%.json}.expected"
+      if [[ -z "$decoder" && -f "${json%.json}.regex.expected" ]]; then
+        expected="${json%.json}.regex.expected"
+      fi
+      JSON_DECODER="$decoder" json_fields "$(cat "$json")" >"${WORK}/${name}.fields"
+      check_output "json_fields ${name} (${decoder:-regex})" "${WORK}/${name}.fields" "$expected"
+    done
+  done
+}
+
+test_normalize() {
+  local input expected got
+  while IFS=$'\t' read -r input expected; do
+    [[ -n "$input" && "$input" != \#* ]] || continue
+    got="$(normalize_number "$input")"
+    if [[ "$got" == "$expected" ]]; then
+      pass
+    else
+      fail "normalize_number '${input}'" "expected '${expected}', got '${got}'"
+    fi
+  done <"${FIXTURES}/normalize.tsv"
+}
+
+# Runs the wrapper on "$@" with the stub openclaw; sets rc, sent (the
+# messages it sent) and leaves its stdout and stderr in WORK/stdout and
+# WORK/stderr.
+run_wrapper() {
+  : >"${WORK}/openclaw.out"
+  PATH="${TESTS_DIR}/stub:${PATH}" OPENCLAW_STUB_OUT="${WORK}/openclaw.out" \
+    "$WRAPPER" --channel test --target 1 --openclaw-format plain "$@" >"${WORK}/stdout" 2>"${WORK}/stderr"
+  rc=$?
+  sent="$(cat "${WORK}/openclaw.out")"
+}
+
+# Checks that the last run_wrapper exited with $2 and sent a message
+# matching the grep -E pattern $3.
+expect_run() {
+  if (( rc != $2 )); then
+    fail "$1" "expected exit $2, got ${rc}"
+  elif ! grep -Eq -- "$3" <<<"$sent"; then
+    fail "$1" "no message matching /$3/ in:"$'\n'"${sent}"
+  else
+    pass
+  fi
+}
+
+# Like run_wrapper for "${@:2}", but sends SIGTERM to the wrapper after $1
+# seconds.
+signal_wrapper() {
+  : >"${WORK}/openclaw.out"
+  PATH="${TESTS_DIR}/stub:${PATH}" OPENCLAW_STUB_OUT="${WORK}/openclaw.out" \
+    "$WRAPPER" --channel test --target 1 --openclaw-format plain "${@:2}" >"${WORK}/stdout" 2>/dev/null &
+  local pid=$!
+  sleep "$1"
+  kill -TERM "$pid"
+  wait "$pid"
+  rc=$?
+  sent="$(cat "${WORK}/openclaw.out")"
+}
+
+# Checks that file $2 holds exactly the bytes printf '%b' "$3" prints.
+expect_bytes() {
+  printf '%b' "$3" >"${WORK}/expected"
+  if cmp -s "${WORK}/expected" "$2"; then
+    pass
+  else
+    fail "$1" "expected $(od -c "${WORK}/expected" | head -5)"$'\n'"got $(od -c "$2" | head -5)"
+  fi
+}
+
+test_wrapper() {
+  run_wrapper --label ok -- bash -c 'echo hello'
+  expect_run "wrapper success" 0 '⚒️ ok completed \(exit=0\)'
+  run_wrapper --label bad -- bash -c 'echo boom; exit 3'
+  expect_run "wrapper failure" 3 '⚒️ bad failed \(exit=3\)'
+  expect_run "wrapper failure tail" 3 '^boom$'
+  run_wrapper --label slow --timeout 1 -- sleep 30
+  expect_run "wrapper timeout" 143 '⏱️ slow timed out after 1s'
+  run_wrapper --label sort -- sort <<<$'b\na'
+  expect_bytes "wrapper stdin" "${WORK}/stdout" 'a\nb\n'
+  run_wrapper --label piped --stdin <<<$'one\ntwo'
+  expect_bytes "wrapper --stdin" "${WORK}/stdout" 'one\ntwo\n'
+  run_wrapper --label sampled --sample-lines 1/10 --log "${WORK}/sampled.log" -- bash -c 'seq 1000; exit 1'
+  seq 1000 >"${WORK}/seq"
+  expect_bytes "wrapper --sample-lines log" "${WORK}/sampled.log" "$(cat "${WORK}/seq")\n"
+  expect_run "wrapper --sample-lines tail" 1 '^999$'
+  # Progress bar redraws pass through as they are but still get parsed.
+  local bar='a\r\n 10%|#  | 1/10\r 60%|##  | 6/10\rtail'
+  run_wrapper --label cr -- printf '%b' "$bar"
+  expect_bytes "wrapper CR passthrough" "${WORK}/stdout" "$bar"
+  expect_run "wrapper CR progress" 0 '📈 cr: 60% \(6/10\)'
+  run_wrapper --label crlog --log "${WORK}/cr.log" -- printf '%b' "$bar"
+  expect_bytes "wrapper CR log" "${WORK}/cr.log" "$bar"
+  run_wrapper --label fifo --sh 'echo "{\"percent\": 60}" >"$OCNOTIFY_PROGRESS_FIFO"; sleep 1.5'
+  expect_run "wrapper progress FIFO" 0 '📈 fifo: 60%'
+  # Oversized and deeply nested reports are dropped with one warning, and
+  # later ones still count.
+  run_wrapper --label limits --sh 'big="$(head -c 70000 /dev/zero | tr "\0" a)"
+    echo "::ocnotify::{\"percent\": 10, \"note\": \"${big}\"}"
+    printf "::ocnotify::{\"percent\": 20, \"a\": %s1%s}\n" "$(printf "[%.0s" {1..40})" "$(printf "]%.0s" {1..40})"
+    echo "::ocnotify::{\"percent\": 60}"'
+  expect_run "wrapper drops oversized reports" 0 '📈 limits: 60%'
+  if [[ "$(grep -c 'dropped a progress report' "${WORK}/stderr")" != 1 ]]; then
+    fail "wrapper warns once about dropped reports" "$(cat "${WORK}/stderr")"
+  elif grep -q '📈 limits: [12]0%' <<<"$sent"; then
+    fail "wrapper ignores dropped reports" "$sent"
+  else
+    pass
+  fi
+  # Smoothed progress never goes back, and its remaining time follows the
+  # weighted rate.
+  run_wrapper --label mono --progress-smoothing monotonic --sh \
+    'echo "::ocnotify::{\"percent\": 60}"; sleep 1.5; echo "::ocnotify::{\"percent\": 10}"; sleep 1.5; exit 1'
+  expect_run "wrapper --progress-smoothing monotonic" 1 '^Progress: 60%$'
+  run_wrapper --label ewma --progress-smoothing ewma --milestones 50 --sh \
+    'for p in 10 20 30 40 55; do echo "::ocnotify::{\"percent\": $p}"; sleep 1.2; done'
+  expect_run "wrapper --progress-smoothing ewma" 0 '^Remaining: ~[0-9]+s$'
+  # Coverage is compared with earlier runs of the label, and a drop past
+  # the tolerance is reported.
+  local pct
+  for pct in 85 85 80; do
+    run_wrapper --label cov --parser coverage --history coverage -- bash -c "echo 'TOTAL   164   24   ${pct}%'"
+  done
+  expect_run "wrapper --history" 0 '^History: coverage=80 \(↓5 ✗ vs median 85 of 2 runs\)$'
+  expect_run "wrapper --history regression" 0 '📉 cov: coverage regressed to 80'
+  run_wrapper --label cov --parser coverage --history coverage --regression-tolerance 10 -- bash -c "echo 'TOTAL   164   24   80%'"
+  if grep -q '📉' <<<"$sent"; then
+    fail "wrapper --regression-tolerance" "$sent"
+  else
+    pass
+  fi
+  if command -v script >/dev/null 2>&1 && [[ "$(uname)" != Darwin ]]; then
+    # Run from a terminal, the child can still open it for prompts.
+    : >"${WORK}/openclaw.out"
+    PATH="${TESTS_DIR}/stub:${PATH}" OPENCLAW_STUB_OUT="${WORK}/openclaw.out" \
+      script -qefc "$(printf '%q ' "$WRAPPER" --channel test --target 1 --openclaw-format plain \
+      --label tty -- bash -c ': </dev/tty')" /dev/null </dev/null >/dev/null 2>&1
+    rc=$?
+    sent="$(cat "${WORK}/openclaw.out")"
+    expect_run "wrapper keeps the terminal" 0 '⚒️ tty completed'
+    # script(1) waits for all its children, so the progress FIFO reader
+    # must not be one of them.
+    run_wrapper --label pty --timeout 20 --pty -- echo hi
+    expect_run "wrapper --pty" 0 '⚒️ pty completed'
+    # Under a terminal, the --pty child's terminal has the same size.
+    PATH="${TESTS_DIR}/stub:${PATH}" OPENCLAW_STUB_OUT="${WORK}/openclaw.out" \
+      script -qefc "stty cols 123 rows 45; $(printf '%q ' "$WRAPPER" --channel test --target 1 \
+      --openclaw-format plain --label size --pty -- stty size)" /dev/null </dev/null >"${WORK}/stdout" 2>&1
+    # script(1) may echo a control character ahead of the output.
+    if tr -d '\r\0' <"${WORK}/stdout" | grep -q '45 123$'; then
+      pass
+    else
+      fail "wrapper --pty size" "expected '45 123', got '$(tr -d '\r\0' <"${WORK}/stdout")'"
+    fi
+  fi
+  # Interrupted runs get the same final report as failed ones.
+  signal_wrapper 2 --label sig --on-failure 'echo hooked' -- bash -c 'echo started; sleep 30'
+  expect_run "wrapper SIGTERM" 143 '🛑 sig cancelled .*SIGTERM \(exit=143\)'
+  expect_run "wrapper SIGTERM hook" 143 '^Hooks: on-failure: exit 0 \(hooked\)$'
+  expect_run "wrapper SIGTERM tail" 143 '^started$'
+  if [[ -r /proc/loadavg ]]; then
+    expect_run "wrapper SIGTERM host context" 143 '^Host context: load '
+  fi
+  signal_wrapper 3 --label backoff --retries 1 --retry-backoff 60 -- bash -c 'exit 4'
+  expect_run "wrapper SIGTERM in backoff" 143 '🛑 backoff cancelled .*SIGTERM \(exit=4\)'
+}
+
+# The SSH wrapper against the ssh and scp stubs, which run the "remote"
+# side on this machine.
+test_ssh_wrapper() {
+  local dir
+  WRAPPER="$SSH_WRAPPER" run_wrapper --host stub --label rok -- 'echo hello'
+  expect_run "ssh wrapper success" 0 '⚒️ rok on stub completed \(exit=0\)'
+  expect_bytes "ssh wrapper stdout" "${WORK}/stdout" 'hello\n'
+  WRAPPER="$SSH_WRAPPER" run_wrapper --host stub --label rbad -- 'echo boom; exit 3'
+  expect_run "ssh wrapper failure" 3 '⚒️ rbad on stub failed \(exit=3\)'
+  expect_run "ssh wrapper failure tail" 3 '^boom$'
+  WRAPPER="$SSH_WRAPPER" run_wrapper --host stub --label rslow --timeout 1 -- 'sleep 30'
+  expect_run "ssh wrapper timeout" 255 '⏱️ rslow on stub timed out after 1s'
+  WRAPPER="$SSH_WRAPPER" signal_wrapper 2 --host stub --label rsig -- 'echo started; sleep 30'
+  expect_run "ssh wrapper SIGTERM" 143 '🛑 rsig on stub cancelled .*SIGTERM \(exit=255\)'
+  # The run's files on the job host are in a directory only its user can
+  # enter.
+  WRAPPER="$SSH_WRAPPER" run_wrapper --host stub --label rdir -- \
+    'd="$(dirname "$OCNOTIFY_DECISION_FILE")"; ls -ld "$d" | cut -c1-10; [[ "$OCNOTIFY_PROGRESS_FIFO" == "$d"/* ]] && echo same'
+  expect_bytes "ssh wrapper private directory" "${WORK}/stdout" 'drwx------\nsame\n'
+  : >"${WORK}/ssh.log"
+  : >"${WORK}/plot.png"
+  SSH_STUB_LOG="${WORK}/ssh.log" WRAPPER="$SSH_WRAPPER" run_wrapper --host stub --label rclean --plot "${WORK}/plot.png" \
+    -- 'dirname "$OCNOTIFY_DECISION_FILE"'
+  dir="$(cat "${WORK}/stdout")"
+  if [[ -n "$dir" && ! -e "$dir" ]]; then
+    pass
+  else
+    fail "ssh wrapper cleanup" "${dir:-no directory} left behind"
+  fi
+  # All ssh and scp calls share one connection, closed at the end.
+  if grep -qv -- '-o ControlMaster=auto -o ControlPath=' "${WORK}/ssh.log"; then
+    fail "ssh wrapper shared connection" "$(grep -v -- '-o ControlMaster=auto' "${WORK}/ssh.log")"
+  elif [[ "$(tail -n 1 "${WORK}/ssh.log")" != *"-O exit stub" ]]; then
+    fail "ssh wrapper closes its connection" "$(cat "${WORK}/ssh.log")"
+  else
+    pass
+  fi
+}
+
+test_parsers
+test_json_fields
+test_normalize
+test_wrapper
+test_ssh_wrapper
+
+printf '%d passed, %d failed\n' "$passed" "$failed"
+(( failed == 0 ))